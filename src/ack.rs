@@ -0,0 +1,141 @@
+use crate::{datagram, pkt_buf, transport};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AckPolicy {
+    delay: Duration,
+    bytes: usize,
+    datagram: bool,
+}
+
+impl AckPolicy {
+    pub fn new(delay: Duration, bytes: usize, datagram: bool) -> Self {
+        Self {
+            delay,
+            bytes,
+            datagram,
+        }
+    }
+}
+
+/// Coalesces acks for delivered packets. Acks are cumulative, so only the latest id
+/// is sent once `delay` has passed or `bytes` have been delivered since the last ack.
+pub struct Acker {
+    policy: AckPolicy,
+    conn: transport::Connection,
+    stream: transport::SendStream,
+    datagram: bool,
+    pending: Option<u32>,
+    pending_bytes: usize,
+    deadline: Option<Instant>,
+    // datagram acks can be lost, so the last one is repeated once, then sent on the stream
+    repeat: Option<(u32, Instant)>,
+    repeated: bool,
+}
+
+impl Acker {
+    pub fn new(
+        policy: AckPolicy,
+        conn: transport::Connection,
+        stream: transport::SendStream,
+    ) -> Self {
+        let datagram = policy.datagram && conn.max_datagram_size().is_some();
+        if policy.datagram && !datagram {
+            log::debug!("peer does not accept datagrams, acking on the stream");
+        }
+        Self {
+            policy,
+            conn,
+            stream,
+            datagram,
+            pending: None,
+            pending_bytes: 0,
+            deadline: None,
+            repeat: None,
+            repeated: false,
+        }
+    }
+
+    pub async fn delivered(&mut self, id: u32, bytes: usize) -> Result<()> {
+        self.pending = Some(id);
+        self.pending_bytes += bytes;
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.policy.delay);
+        }
+        if self.pending_bytes >= self.policy.bytes || self.policy.delay.is_zero() {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Resolves when a coalesced or repeated ack is due. Only waits, so it is safe to race
+    /// against reads; call `on_timer` afterwards.
+    pub async fn expired(&self) {
+        let at = match (self.deadline, self.repeat) {
+            (Some(deadline), _) => deadline,
+            (None, Some((_, at))) => at,
+            (None, None) => return std::future::pending().await,
+        };
+        tokio::time::sleep_until(at).await;
+    }
+
+    pub async fn on_timer(&mut self) -> Result<()> {
+        if self.deadline.is_some() {
+            return self.flush().await;
+        }
+        let id = match self.repeat.take() {
+            Some((id, _)) => id,
+            None => return Ok(()),
+        };
+        // nothing newer came to replace it, so the peer may still be waiting for this one
+        match self.repeated {
+            false => {
+                let _ = self.conn.send_datagram(datagram::ack(id));
+                self.repeated = true;
+                self.repeat = Some((id, Instant::now() + self.repeat_after()));
+            }
+            true => self.stream.write_all(&pkt_buf::to_ack_pkt(id)).await?,
+        }
+        Ok(())
+    }
+
+    fn repeat_after(&self) -> Duration {
+        (self.conn.rtt() * 2).max(self.policy.delay)
+    }
+
+    /// Sends the ack still being coalesced once the peer's stream has ended, on the stream,
+    /// since no repeat follows. The peer may be gone by then, so failing is not an error.
+    pub async fn finish(&mut self) {
+        self.datagram = false;
+        if let Err(e) = self.flush().await {
+            log::debug!("final ack failed: {:?}", e);
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.pending_bytes = 0;
+        self.deadline = None;
+        let id = match self.pending.take() {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        if self.datagram {
+            match self.conn.send_datagram(datagram::ack(id)) {
+                Ok(_) => {
+                    self.repeat = Some((id, Instant::now() + self.repeat_after()));
+                    self.repeated = false;
+                    return Ok(());
+                }
+                Err(e) => {
+                    log::debug!("ack datagram failed, acking on the stream: {:?}", e);
+                    self.datagram = false;
+                    self.repeat = None;
+                }
+            }
+        }
+        self.stream.write_all(&pkt_buf::to_ack_pkt(id)).await?;
+        Ok(())
+    }
+}
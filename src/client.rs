@@ -1,19 +1,40 @@
-use crate::{queue, utils};
+use crate::{
+    endpoints, heartbeat, http_proxy, lb, mux, quic, ratelimit, relay, socks5, transport, udp,
+    utils,
+};
 use anyhow::Result;
 use clap::Parser;
-use std::{sync::Arc, time::Duration};
-use tokio::sync::{Mutex, RwLock};
+use ring::rand::SecureRandom;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    sync::RwLock,
+};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(name = "client")]
 pub struct Opt {
+    #[clap(
+        required_unless_present = "daemon",
+        default_value = "",
+        hide_default_value = true
+    )]
     target: String,
 
-    #[clap(long = "idle", short = 'i', default_value = "3")]
-    idle: u64,
+    #[clap(flatten)]
+    quic: quic::QuicOpt,
 
-    #[clap(long = "keepalive", short = 'k', default_value = "1")]
-    keepalive: u64,
+    #[clap(flatten)]
+    conn: utils::ConnOpt,
 
     #[clap(long = "bufsize", short = 'b', default_value = "18")]
     bufsize: u8,
@@ -23,62 +44,668 @@ pub struct Opt {
 
     #[clap(long = "only-ipv6", short = '6')]
     ipv6: bool,
+
+    #[clap(
+        long = "transport",
+        value_enum,
+        default_value = "auto",
+        hide_possible_values = true
+    )]
+    transport: transport::Kind,
+
+    // defaults to the port of the target
+    #[clap(long = "tcp-port")]
+    tcp_port: Option<u16>,
+
+    #[clap(long = "fallback-timeout", default_value = "3s", value_parser = utils::parse_duration)]
+    fallback_timeout: Duration,
+
+    // defaults to the port of the target
+    #[clap(long = "ws-port")]
+    ws_port: Option<u16>,
+
+    // HTTP proxy for --transport ws, defaults to https_proxy
+    #[clap(long = "proxy")]
+    proxy: Option<String>,
+
+    // SOCKS5 proxy whose UDP relay carries QUIC, defaults to a socks5:// ALL_PROXY
+    #[clap(long = "socks5")]
+    socks5: Option<String>,
+
+    // reach the server registered as the target name through this relay
+    #[clap(long = "relay")]
+    relay: Option<String>,
+
+    // host:port for the server to connect to instead of its --forward target, as a jump host,
+    // e.g. ProxyCommand stablessh client bastion:2222 --dest %h:%p
+    #[clap(long = "dest")]
+    dest: Option<String>,
+
+    // accept local TCP connections here instead of using stdin and stdout, each one a session
+    #[clap(long = "listen", conflicts_with = "socks")]
+    listen: Option<SocketAddr>,
+
+    // serve SOCKS5 here, each CONNECT a session to the destination it asks for, which the
+    // server has to allow with --allow-dest, all over one QUIC connection
+    #[clap(long = "socks", conflicts_with_all = ["dest", "relay"])]
+    socks: Option<SocketAddr>,
+
+    // forward datagrams received here to the server's --udp-forward target, instead of using
+    // stdin and stdout
+    #[clap(long = "udp", conflicts_with_all = ["listen", "socks", "dest"])]
+    udp: Option<SocketAddr>,
+
+    // run the client daemon, which keeps one connection per server for the sessions of
+    // --via-daemon clients
+    #[clap(long = "daemon", conflicts_with_all = ["listen", "socks", "udp", "dest", "via_daemon"])]
+    daemon: bool,
+
+    // hand the session to the client daemon, or connect directly if none is running
+    #[clap(long = "via-daemon", conflicts_with_all = ["listen", "socks", "udp", "dest"])]
+    via_daemon: bool,
+
+    // defaults to stablessh.sock in $XDG_RUNTIME_DIR, or in a directory of our own in /tmp
+    #[clap(long = "daemon-socket")]
+    daemon_socket: Option<PathBuf>,
 }
 
-pub async fn run(opt: Opt) -> Result<()> {
-    let (cert_der, priv_key) = crate::utils::gen_cert()?;
-    let mut client_crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_custom_certificate_verifier(crate::utils::SkipServerVerification::new())
-        .with_client_auth_cert(
-            vec![rustls::Certificate(cert_der.clone())],
-            rustls::PrivateKey(priv_key),
+// a transport that fails at once, like a refused TCP connect, must not spin
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Raised when the server ends a session and says why, rather than losing the connection.
+#[derive(Debug)]
+struct Closed(String);
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "closed by server: {}", self.0)
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// Where a connection attempt goes.
+#[derive(Debug, Clone)]
+enum Target {
+    // an address of the target, or of the relay
+    Addr(SocketAddr),
+    // host:port of the target, resolved by the proxy if there is one
+    Name(String),
+    // an endpoint the server advertised
+    Alternate(endpoints::Endpoint),
+}
+
+/// What a connection attempt needs besides the options.
+struct Dialer {
+    endpoint: quinn::Endpoint,
+    endpoint_config: quinn::EndpointConfig,
+    client_config: quinn::ClientConfig,
+    tcp_crypto: Arc<rustls::ClientConfig>,
+    // --proxy, otherwise https_proxy is looked up for each ws target
+    proxy: Option<http_proxy::Proxy>,
+    socks5: Option<socks5::Proxy>,
+    relay_config: quinn::ClientConfig,
+}
+
+impl Dialer {
+    /// Everything a session needs, under a key of its own: the server tells sessions apart
+    /// by the client certificate.
+    fn new(opt: &Opt) -> Result<Self> {
+        let (cert_der, priv_key) = match (opt.daemon || opt.socks.is_some(), opt.udp) {
+            (true, _) => crate::utils::gen_cert_multiplexed()?,
+            (false, Some(_)) => crate::utils::gen_cert_udp()?,
+            (false, None) => crate::utils::gen_cert_with_dest(opt.dest.as_deref())?,
+        };
+        let mut client_crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(crate::utils::SkipServerVerification::new())
+            .with_client_auth_cert(
+                vec![rustls::Certificate(cert_der.clone())],
+                rustls::PrivateKey(priv_key),
+            )?;
+        client_crypto.alpn_protocols = opt.conn.alpn_protocols();
+        // TLS tickets from QUIC and TCP are kept apart, and only QUIC sends early data
+        let mut tcp_crypto = client_crypto.clone();
+        tcp_crypto.resumption = rustls::client::Resumption::default();
+        client_crypto.enable_early_data = true;
+        let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
+        let mut transport_config = opt.quic.transport_config()?;
+        transport_config.mtu_discovery_config(Some(quinn::MtuDiscoveryConfig::default()));
+        client_config.transport_config(Arc::new(transport_config));
+        // a load balancer in front of the servers pins our new connections by this key
+        let (pubkey, _) = utils::x509(&rustls::Certificate(cert_der))?;
+        let endpoint_config = lb::endpoint_config(lb::ClientKeyGenerator::new(&pubkey));
+        let mut endpoint = quinn::Endpoint::new(
+            endpoint_config.clone(),
+            None,
+            std::net::UdpSocket::bind("[::]:0")?,
+            Arc::new(quinn::TokioRuntime),
+        )?;
+        endpoint.set_default_client_config(client_config.clone());
+
+        let proxy = match &opt.proxy {
+            Some(url) => Some(http_proxy::Proxy::parse(url)?),
+            None => None,
+        };
+        let socks5 = match &opt.socks5 {
+            Some(url) => Some(socks5::Proxy::parse(url)?),
+            None => socks5::Proxy::from_env()?,
+        };
+        Ok(Self {
+            endpoint,
+            endpoint_config,
+            client_config,
+            tcp_crypto: Arc::new(tcp_crypto),
+            proxy,
+            socks5,
+            relay_config: relay::client_config(&opt.quic)?,
+        })
+    }
+
+    /// The HTTP proxy for a ws connection to `target`.
+    fn ws_proxy(&self, target: &str) -> Option<http_proxy::Proxy> {
+        match &self.proxy {
+            Some(proxy) => Some(proxy.clone()),
+            None => http_proxy::Proxy::from_env(target),
+        }
+    }
+
+    /// The endpoint for the next QUIC attempt. Through SOCKS5 every attempt sets up a new
+    /// association, so a relay that dropped ours is not reused.
+    async fn quic_endpoint(&self) -> Result<quinn::Endpoint> {
+        let socks5 = match &self.socks5 {
+            Some(socks5) => socks5,
+            None => return Ok(self.endpoint.clone()),
+        };
+        let mut endpoint = quinn::Endpoint::new_with_abstract_socket(
+            self.endpoint_config.clone(),
+            None,
+            socks5.udp_associate().await?,
+            Arc::new(quinn::TokioRuntime),
         )?;
-    client_crypto.alpn_protocols = vec![b"stablessh".to_vec()];
-    let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
-    let mut transport_config = quinn::TransportConfig::default();
-    transport_config.mtu_discovery_config(Some(quinn::MtuDiscoveryConfig::default()));
-    if opt.idle > 0 {
-        transport_config.max_idle_timeout(Some(Duration::from_secs(opt.idle).try_into()?));
+        endpoint.set_default_client_config(self.client_config.clone());
+        Ok(endpoint)
+    }
+}
+
+pub async fn run(opt: Opt) -> Result<()> {
+    if opt.daemon {
+        return daemon(opt).await;
+    }
+    if opt.via_daemon {
+        match daemon_connect(&daemon_socket(&opt)?).await {
+            Ok(unix) => return via_daemon(&opt, unix).await,
+            Err(e) => log::debug!("No client daemon, connecting directly: {:#}", e),
+        }
+    }
+    if let Some(addr) = opt.listen {
+        return listen(opt, addr, false).await;
+    }
+    if let Some(addr) = opt.socks {
+        return listen(opt, addr, true).await;
+    }
+    let dialer = Dialer::new(&opt)?;
+    if opt.udp.is_some() {
+        // the session carries datagrams only
+        return connect(&opt, dialer, udp::Idle, tokio::io::sink()).await;
+    }
+    let std_recv = tokio::io::BufReader::new(tokio::io::stdin());
+    let std_send = tokio::io::BufWriter::new(tokio::io::stdout());
+    connect(&opt, dialer, std_recv, std_send).await?;
+
+    Ok(())
+}
+
+/// Serves each local TCP connection as a session of its own, which resumes on its own.
+/// With `socks` the connections speak SOCKS5 and each goes where it asks, as a stream of one
+/// connection to the server, like the sessions of the client daemon.
+async fn listen(opt: Opt, addr: SocketAddr, socks: bool) -> Result<()> {
+    let link = match socks {
+        true if matches!(opt.transport, transport::Kind::Tcp | transport::Kind::Ws) => {
+            return Err(anyhow::anyhow!("--socks only runs over QUIC"));
+        }
+        true => Some(Arc::new(Link::new(&opt, &opt.target)?)),
+        false => None,
+    };
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    log::info!("Listening on {}", listener.local_addr()?);
+    loop {
+        let (mut tcp, peer) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("TCP accept error: {:?}", e);
+                continue;
+            }
+        };
+        let opt = opt.clone();
+        let link = link.clone();
+        tokio::spawn(async move {
+            let ret = async {
+                if let Some(link) = link {
+                    let dest = socks5::accept(&mut tcp).await?;
+                    log::info!("New session for {} to {}", peer, dest);
+                    return socks_session(&link, &dest, tcp).await;
+                }
+                log::info!("New session for {}", peer);
+                let dialer = Dialer::new(&opt)?;
+                let (recv, send) = tcp.into_split();
+                connect(&opt, dialer, Local(recv), Local(send)).await
+            };
+            match ret.await {
+                Ok(_) => log::info!("Session for {} closed", peer),
+                Err(e) => log::error!("Session for {}: {:#}", peer, e),
+            }
+        });
     }
-    if opt.keepalive > 0 {
-        transport_config.keep_alive_interval(Some(Duration::from_secs(opt.keepalive)));
+}
+
+/// Runs the session of a SOCKS5 client whose CONNECT `accept` took. The client is told it is
+/// connected only once the server has reached `dest`, or why the server gave up.
+async fn socks_session(link: &Link, dest: &str, tcp: tokio::net::TcpStream) -> Result<()> {
+    let (recv, mut send) = tcp.into_split();
+    let session = utils::Session::new(link.opt.bufsize, None, ratelimit::Shaper::unlimited());
+    let established = session.established.clone();
+    // what the server sends waits here until the reply is out
+    let (down, mut pending) = tokio::io::duplex(65536);
+    let run = link.session(Some(dest), session, Local(recv), Local(down));
+    tokio::pin!(run);
+    tokio::select! {
+        ret = &mut run => {
+            let reason = match &ret {
+                Err(e) => e.downcast_ref::<Closed>().map(|closed| closed.0.as_str()),
+                Ok(_) => None,
+            };
+            socks5::failed(&mut send, reason).await?;
+            return ret;
+        }
+        _ = established.notified() => socks5::connected(&mut send).await?,
+    }
+    let down = async {
+        tokio::io::copy(&mut pending, &mut send).await?;
+        send.shutdown().await?;
+        Ok(())
+    };
+    tokio::try_join!(run, down)?;
+    Ok(())
+}
+
+/// Without `$XDG_RUNTIME_DIR` the socket goes in a directory of our own in /tmp, where a
+/// name anyone can guess could otherwise be taken by another user first.
+fn daemon_socket(opt: &Opt) -> Result<PathBuf> {
+    if let Some(path) = &opt.daemon_socket {
+        return Ok(path.clone());
     }
-    client_config.transport_config(Arc::new(transport_config));
-    let mut endpoint = quinn::Endpoint::client("[::]:0".parse()?)?;
-    endpoint.set_default_client_config(client_config);
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let dir = std::env::temp_dir().join(format!("stablessh-{}", uid()));
+            match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(anyhow::anyhow!("{}: {}", dir.display(), e)),
+            }
+            check_owner(&dir)?;
+            dir
+        }
+    };
+    Ok(dir.join("stablessh.sock"))
+}
 
-    connect(opt, endpoint).await?;
+fn uid() -> u32 {
+    // SAFETY: getuid has no preconditions and can't fail
+    unsafe { libc::getuid() }
+}
 
+/// Fails unless `path` belongs to us, and for a directory, to nobody else either.
+fn check_owner(path: &Path) -> Result<()> {
+    let meta = std::fs::symlink_metadata(path)?;
+    if meta.uid() != uid() {
+        return Err(anyhow::anyhow!(
+            "{}: owned by uid {}, not by us",
+            path.display(),
+            meta.uid()
+        ));
+    }
+    if meta.is_dir() && meta.mode() & 0o077 != 0 {
+        return Err(anyhow::anyhow!(
+            "{}: open to other users, mode {:o}",
+            path.display(),
+            meta.mode() & 0o777
+        ));
+    }
     Ok(())
 }
 
-async fn connect(opt: Opt, endpoint: quinn::Endpoint) -> Result<()> {
-    let mut std_recv = tokio::io::BufReader::new(tokio::io::stdin());
-    let mut std_send = tokio::io::BufWriter::new(tokio::io::stdout());
-    let q = Arc::new(Mutex::new(queue::Queue::new(opt.bufsize)));
-    let last_ack = Arc::new(RwLock::new(0_u32));
-    let targets = utils::resolve(&opt.target, opt.ipv4, opt.ipv6)?;
+/// Connects to the client daemon, if the socket is ours. Another user's daemon would see
+/// and could answer for all of our sessions.
+async fn daemon_connect(path: &Path) -> Result<tokio::net::UnixStream> {
+    check_owner(path)?;
+    Ok(tokio::net::UnixStream::connect(path).await?)
+}
+
+/// Runs the client daemon. A `--via-daemon` client sends the target on a line of its own,
+/// then its stdin and stdout become a session, over the connection to that server the
+/// daemon keeps for all of them.
+async fn daemon(opt: Opt) -> Result<()> {
+    let path = daemon_socket(&opt)?;
+    // a socket left behind by a daemon that is gone is in the way, one that answers is not ours
+    if tokio::net::UnixStream::connect(&path).await.is_err() {
+        let _ = std::fs::remove_file(&path);
+    }
+    let listener = tokio::net::UnixListener::bind(&path)
+        .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    log::info!("Listening on {}", path.display());
+    let links: Arc<Mutex<HashMap<String, Arc<Link>>>> = Default::default();
+    loop {
+        let (unix, _) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Unix accept error: {:?}", e);
+                continue;
+            }
+        };
+        let opt = opt.clone();
+        let links = links.clone();
+        tokio::spawn(async move {
+            let (recv, send) = unix.into_split();
+            let mut recv = tokio::io::BufReader::new(recv);
+            let mut target = String::new();
+            let ret = async {
+                recv.read_line(&mut target).await?;
+                target.truncate(target.trim_end().len());
+                let link = {
+                    let mut links = links.lock().unwrap();
+                    match links.get(&target) {
+                        Some(link) => link.clone(),
+                        None => {
+                            let link = Arc::new(Link::new(&opt, &target)?);
+                            links.insert(target.clone(), link.clone());
+                            link
+                        }
+                    }
+                };
+                log::info!("New session to {}", target);
+                let session =
+                    utils::Session::new(opt.bufsize, None, ratelimit::Shaper::unlimited());
+                link.session(None, session, Local(recv), Local(send)).await
+            };
+            match ret.await {
+                Ok(_) => log::info!("Session to {} closed", target),
+                Err(e) => log::error!("Session to {}: {:#}", target, e),
+            }
+        });
+    }
+}
+
+/// Passes stdin and stdout through the client daemon.
+async fn via_daemon(opt: &Opt, unix: tokio::net::UnixStream) -> Result<()> {
+    let (mut recv, mut send) = unix.into_split();
+    send.write_all(format!("{}\n", opt.target).as_bytes())
+        .await?;
+    let up = async {
+        tokio::io::copy(&mut tokio::io::stdin(), &mut send).await?;
+        send.shutdown().await?;
+        // what the server still has to say comes down
+        std::future::pending().await
+    };
+    let down = async {
+        tokio::io::copy(&mut recv, &mut tokio::io::stdout()).await?;
+        Ok(())
+    };
+    tokio::select! {
+        ret = up => ret,
+        ret = down => ret,
+    }
+}
+
+/// The client daemon's connection to a server, shared by the sessions to it. Each session
+/// runs over a stream of its own, with its own buffer and resume state, and survives the
+/// connection like any session.
+struct Link {
+    opt: Opt,
+    dialer: Dialer,
+    conn: tokio::sync::Mutex<Option<quinn::Connection>>,
+}
+
+impl Link {
+    fn new(opt: &Opt, target: &str) -> Result<Self> {
+        let mut opt = opt.clone();
+        opt.target = target.to_string();
+        let dialer = Dialer::new(&opt)?;
+        Ok(Self {
+            opt,
+            dialer,
+            conn: Default::default(),
+        })
+    }
+
+    /// A new stream for session `id` to `dest`, over the connection, which is made again if
+    /// it is gone.
+    async fn open(&self, id: &[u8], dest: Option<&str>) -> Result<transport::Connection> {
+        let conn = {
+            let mut conn = self.conn.lock().await;
+            match &*conn {
+                Some(c) if c.close_reason().is_none() => c.clone(),
+                _ => {
+                    let c = self.connect().await?;
+                    *conn = Some(c.clone());
+                    c
+                }
+            }
+        };
+        let (mut send, recv) = conn.open_bi().await?;
+        transport::write_session_header(&mut send, id, dest).await?;
+        Ok(transport::multiplexed(
+            &conn,
+            send,
+            recv,
+            id.to_vec(),
+            None,
+            true,
+        ))
+    }
+
+    // the daemon only speaks QUIC, the connection it keeps alive is what it is for
+    async fn connect(&self) -> Result<quinn::Connection> {
+        let endpoint = self.dialer.quic_endpoint().await?;
+        let server_name = utils::server_name(&self.opt.target);
+        let mut last = anyhow::anyhow!("target not found");
+        for addr in utils::resolve(&self.opt.target, self.opt.ipv4, self.opt.ipv6)? {
+            log::debug!("Connecting to {}", addr);
+            match endpoint.connect(addr, server_name)?.await {
+                Ok(conn) => return Ok(conn),
+                Err(e) => last = e.into(),
+            }
+        }
+        Err(last)
+    }
+
+    async fn session<
+        Reader: tokio::io::AsyncRead + Send + Sync + Unpin,
+        Writer: tokio::io::AsyncWrite + Send + Sync + Unpin,
+    >(
+        &self,
+        dest: Option<&str>,
+        session: utils::Session,
+        mut recv: Reader,
+        mut send: Writer,
+    ) -> Result<()> {
+        let mut id = [0; transport::SESSION_ID_LEN];
+        ring::rand::SystemRandom::new()
+            .fill(&mut id)
+            .map_err(|_| anyhow::anyhow!("failed to generate a session ID"))?;
+        let learned = Arc::new(RwLock::new(Vec::new()));
+        let mut last_attempt: Option<tokio::time::Instant> = None;
+        loop {
+            if let Some(last_attempt) = last_attempt {
+                tokio::time::sleep_until(last_attempt + RETRY_INTERVAL).await;
+            }
+            last_attempt = Some(tokio::time::Instant::now());
+            let ret = async {
+                let conn = self.open(&id, dest).await?;
+                utils::handle_connection(
+                    conn,
+                    None,
+                    self.opt.conn.config().without_heartbeat(),
+                    session.clone(),
+                    endpoints::Endpoints::Learned(learned.clone()),
+                    &mut recv,
+                    &mut send,
+                )
+                .await
+            };
+            let e = match ret.await {
+                Ok(_) => return Ok(()),
+                Err(e) => e,
+            };
+            if let Some(reason) = close_reason(&e) {
+                return Err(Closed(reason).into());
+            }
+            if is_retry(&e) || e.downcast_ref::<quinn::ConnectError>().is_some() {
+                log::debug!("Session to {} interrupted: {:#}", self.opt.target, e);
+                continue;
+            }
+            if is_ok(&e) {
+                return Ok(());
+            }
+            return Err(e);
+        }
+    }
+}
+
+/// A local connection. Its errors end the session rather than being taken for a lost
+/// connection to the server and retried.
+struct Local<T>(T);
+
+impl<T: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for Local<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0)
+            .poll_read(cx, buf)
+            .map_err(std::io::Error::other)
+    }
+}
+
+impl<T: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for Local<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0)
+            .poll_write(cx, buf)
+            .map_err(std::io::Error::other)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0)
+            .poll_flush(cx)
+            .map_err(std::io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0)
+            .poll_shutdown(cx)
+            .map_err(std::io::Error::other)
+    }
+}
+
+async fn connect<
+    Reader: tokio::io::AsyncRead + Send + Sync + Unpin,
+    Writer: tokio::io::AsyncWrite + Send + Sync + Unpin,
+>(
+    opt: &Opt,
+    dialer: Dialer,
+    mut std_recv: Reader,
+    mut std_send: Writer,
+) -> Result<()> {
+    let mut session = utils::Session::new(opt.bufsize, None, ratelimit::Shaper::unlimited());
+    if let Some(addr) = opt.udp {
+        session = session.with_udp(udp::Tunnel::bind(addr).await?);
+    }
+    let targets: Vec<Target> = match (&opt.relay, opt.transport) {
+        (Some(relay), _) => utils::resolve(relay, opt.ipv4, opt.ipv6)?
+            .into_iter()
+            .map(Target::Addr)
+            .collect(),
+        (None, transport::Kind::Ws) => {
+            let (host, ports) = utils::split_ports(&opt.target)?;
+            ports
+                .into_iter()
+                .map(|port| Target::Name(format!("{}:{}", host, port)))
+                .collect()
+        }
+        (None, _) => utils::resolve(&opt.target, opt.ipv4, opt.ipv6)?
+            .into_iter()
+            .map(Target::Addr)
+            .collect(),
+    };
+    let learned = Arc::new(RwLock::new(Vec::new()));
+    let mut last_attempt: Option<tokio::time::Instant> = None;
+    // where the next round through the target's addresses and ports starts
+    let mut start = 0;
     'outer: loop {
-        for target in targets.clone() {
+        let mut retry = false;
+        let own = (0..targets.len()).map(|i| (start + i) % targets.len());
+        let own = own.map(|i| (Some(i), targets[i].clone()));
+        // tried after the target's own addresses, and not through a relay, which only knows names
+        let alternates = match opt.relay {
+            Some(_) => Vec::new(),
+            None => learned.read().await.clone(),
+        };
+        let alternates = alternates.into_iter().map(|e| (None, Target::Alternate(e)));
+        for (index, target) in own.chain(alternates) {
+            if let Some(last_attempt) = last_attempt {
+                tokio::time::sleep_until(last_attempt + RETRY_INTERVAL).await;
+            }
+            last_attempt = Some(tokio::time::Instant::now());
             log::debug!("Connecting to {:?}", target);
-            let conn = match endpoint.connect(target, "localhost") {
-                Ok(conn) => conn,
-                Err(_) => continue,
+            let mut connected = false;
+            let ret = async {
+                let (conn, rx_stream) = dial(opt, &dialer, &target, &session).await?;
+                connected = true;
+                utils::handle_connection(
+                    conn,
+                    rx_stream,
+                    opt.conn.config(),
+                    session.clone(),
+                    endpoints::Endpoints::Learned(learned.clone()),
+                    &mut std_recv,
+                    &mut std_send,
+                )
+                .await
             };
-            match handle_connection(
-                conn,
-                q.clone(),
-                last_ack.clone(),
-                &mut std_recv,
-                &mut std_send,
-            )
-            .await
-            {
-                Ok(_) => return Ok(()),
+            match ret.await {
+                Ok(_) => {
+                    if let Some(ratio) = session.stats.ratio() {
+                        log::debug!("compression ratio: {:.2}", ratio);
+                    }
+                    return Ok(());
+                }
                 Err(e) => {
-                    if is_retry(&e) {
-                        continue 'outer;
+                    if e.downcast_ref::<quinn::ConnectError>().is_some() {
+                        continue;
+                    }
+                    if let Some(reason) = close_reason(&e) {
+                        return Err(Closed(reason).into());
+                    }
+                    let alternate = matches!(target, Target::Alternate(_));
+                    if is_retry(&e) || (alternate && !connected) {
+                        // a lost connection starts over from the target's next address or port,
+                        // a failed attempt moves on to the next endpoint
+                        if connected {
+                            if let Some(i) = index {
+                                start = (i + 1) % targets.len();
+                            }
+                            continue 'outer;
+                        }
+                        log::debug!("Connecting to {:?} failed: {:#}", target, e);
+                        retry = true;
+                        continue;
                     }
                     if is_ok(&e) {
                         return Ok(());
@@ -87,23 +714,171 @@ async fn connect(opt: Opt, endpoint: quinn::Endpoint) -> Result<()> {
                 }
             }
         }
-        return Err(anyhow::anyhow!("target not found"));
+        if !retry {
+            return Err(anyhow::anyhow!("target not found"));
+        }
     }
 }
 
-async fn handle_connection(
-    conn: quinn::Connecting,
-    q: Arc<Mutex<queue::Queue>>,
-    last_ack: Arc<RwLock<u32>>,
-    std_recv: &mut tokio::io::BufReader<tokio::io::Stdin>,
-    std_send: &mut tokio::io::BufWriter<tokio::io::Stdout>,
-) -> Result<()> {
-    let conn = conn.await?;
-    utils::handle_connection(conn, q, last_ack, std_recv, std_send).await?;
-    Ok(())
+type RxStream = (transport::SendStream, transport::RecvStream);
+
+/// Connects with the chosen transport. In auto mode every attempt, reconnects included,
+/// tries QUIC first, so a session moves back to QUIC once UDP gets through again.
+async fn dial(
+    opt: &Opt,
+    dialer: &Dialer,
+    target: &Target,
+    session: &utils::Session,
+) -> Result<(transport::Connection, Option<RxStream>)> {
+    let server_name = utils::server_name(&opt.target);
+    let tcp_crypto = dialer.tcp_crypto.clone();
+    let target = match target {
+        Target::Addr(addr) => *addr,
+        // only ws connects by name
+        Target::Name(name) => {
+            let target = match opt.ws_port {
+                Some(port) if server_name.contains(':') => format!("[{}]:{}", server_name, port),
+                Some(port) => format!("{}:{}", server_name, port),
+                None => name.clone(),
+            };
+            let proxy = dialer.ws_proxy(&target);
+            let conn =
+                transport::connect_ws(tcp_crypto, &target, server_name, proxy.as_ref()).await?;
+            return Ok((conn, None));
+        }
+        Target::Alternate(endpoint) => {
+            return dial_alternate(opt, dialer, endpoint, server_name, session).await
+        }
+    };
+    let tcp_target = |mut target: SocketAddr| {
+        if let Some(port) = opt.tcp_port {
+            target.set_port(port);
+        }
+        target
+    };
+    if let Some(relay) = &opt.relay {
+        let endpoint = dialer.quic_endpoint().await?;
+        let relay = endpoint
+            .connect_with(
+                dialer.relay_config.clone(),
+                target,
+                utils::server_name(relay),
+            )?
+            .await?;
+        let stream = relay::connect(&relay, &opt.target).await?;
+        let conn = transport::connect_relayed(tcp_crypto, stream, target, &opt.target).await?;
+        return Ok((conn, None));
+    }
+    match opt.transport {
+        transport::Kind::Ws => unreachable!("ws connects by name"),
+        transport::Kind::Quic => {
+            let endpoint = dialer.quic_endpoint().await?;
+            connect_quic(&endpoint, target, server_name, session).await
+        }
+        transport::Kind::Tcp => {
+            let conn = transport::connect_tcp(tcp_crypto, tcp_target(target), server_name).await?;
+            Ok((conn, None))
+        }
+        transport::Kind::Auto => {
+            let quic = async {
+                let endpoint = dialer.quic_endpoint().await?;
+                connect_quic(&endpoint, target, server_name, session).await
+            };
+            match tokio::time::timeout(opt.fallback_timeout, quic).await {
+                Ok(Ok(v)) => return Ok(v),
+                Ok(Err(e)) => log::debug!("QUIC failed, falling back to TCP: {:?}", e),
+                Err(_) => log::debug!("QUIC timed out, falling back to TCP"),
+            }
+            let conn = transport::connect_tcp(tcp_crypto, tcp_target(target), server_name).await?;
+            Ok((conn, None))
+        }
+    }
+}
+
+/// Connects to an endpoint the server advertised, over the transport it names. The server
+/// name stays the target's, so TLS tickets from the target are used here too.
+async fn dial_alternate(
+    opt: &Opt,
+    dialer: &Dialer,
+    endpoint: &endpoints::Endpoint,
+    server_name: &str,
+    session: &utils::Session,
+) -> Result<(transport::Connection, Option<RxStream>)> {
+    let tcp_crypto = dialer.tcp_crypto.clone();
+    if endpoint.kind == transport::Kind::Ws {
+        let proxy = dialer.ws_proxy(&endpoint.addr);
+        let conn =
+            transport::connect_ws(tcp_crypto, &endpoint.addr, server_name, proxy.as_ref()).await?;
+        return Ok((conn, None));
+    }
+    let addr = utils::resolve(&endpoint.addr, opt.ipv4, opt.ipv6)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("could not resolve {}", endpoint.addr))?;
+    match endpoint.kind {
+        transport::Kind::Tcp => {
+            let conn = transport::connect_tcp(tcp_crypto, addr, server_name).await?;
+            Ok((conn, None))
+        }
+        _ => {
+            let quic_endpoint = dialer.quic_endpoint().await?;
+            connect_quic(&quic_endpoint, addr, server_name, session).await
+        }
+    }
+}
+
+async fn connect_quic(
+    endpoint: &quinn::Endpoint,
+    target: SocketAddr,
+    server_name: &str,
+    session: &utils::Session,
+) -> Result<(transport::Connection, Option<RxStream>)> {
+    let conn = endpoint.connect(target, server_name)?;
+    match conn.into_0rtt() {
+        Ok((quic, accepted)) => {
+            log::debug!("Resuming with 0-RTT");
+            let conn = transport::Connection::Quic(quic.clone());
+            let rx_stream = utils::open_rx(&conn, session.last_ack.clone()).await?;
+            let accepted = accepted.await;
+            // a handshake that failed also reports early data as rejected
+            if let Some(e) = quic.close_reason() {
+                return Err(e.into());
+            }
+            if accepted {
+                Ok((conn, Some(rx_stream)))
+            } else {
+                log::debug!("0-RTT rejected");
+                Ok((conn, None))
+            }
+        }
+        Err(conn) => Ok((transport::Connection::Quic(conn.await?), None)),
+    }
+}
+
+/// Why the server closed the connection, if it said. Such a close is not retried.
+fn close_reason(e: &anyhow::Error) -> Option<String> {
+    if let Some(mux::Error::ClosedWith(reason)) = e.downcast_ref() {
+        return Some(reason.clone());
+    }
+    let e = if let Some(quinn::ReadError::ConnectionLost(e)) = e.downcast_ref() {
+        e
+    } else if let Some(quinn::WriteError::ConnectionLost(e)) = e.downcast_ref() {
+        e
+    } else {
+        e.downcast_ref::<quinn::ConnectionError>()?
+    };
+    match e {
+        quinn::ConnectionError::ApplicationClosed(close) if !close.reason.is_empty() => {
+            Some(String::from_utf8_lossy(&close.reason).into_owned())
+        }
+        _ => None,
+    }
 }
 
 fn is_ok(e: &anyhow::Error) -> bool {
+    if matches!(e.downcast_ref(), Some(mux::Error::Closed)) {
+        return true;
+    }
     if matches!(
         e.downcast_ref(),
         Some(quinn::WriteError::ConnectionLost(
@@ -124,6 +899,27 @@ fn is_ok(e: &anyhow::Error) -> bool {
 }
 
 fn is_retry(e: &anyhow::Error) -> bool {
+    if e.downcast_ref::<heartbeat::Timeout>().is_some() {
+        return true;
+    }
+    if matches!(e.downcast_ref(), Some(mux::Error::Lost(_))) {
+        return true;
+    }
+    // the relay restarted, and the server may not have registered again yet
+    if e.downcast_ref::<relay::Unavailable>().is_some() {
+        return true;
+    }
+    // connecting over TCP failed, stdin and stdout errors are not retried
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return matches!(
+            e.kind(),
+            std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::UnexpectedEof
+        );
+    }
     if matches!(e.downcast_ref(), Some(quinn::ConnectionError::TimedOut)) {
         return true;
     }
@@ -151,3 +947,20 @@ fn is_retry(e: &anyhow::Error) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod test {
+    use super::check_owner;
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    #[test]
+    fn test_check_owner() {
+        let dir = std::env::temp_dir().join(format!("stablessh-test-{}", std::process::id()));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        assert!(check_owner(&dir).is_ok());
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(check_owner(&dir).is_err());
+        std::fs::remove_dir(&dir).unwrap();
+        assert!(check_owner(&dir).is_err());
+    }
+}
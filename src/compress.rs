@@ -0,0 +1,232 @@
+use crate::transport;
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    OnceLock,
+};
+
+pub const ALPN: &[u8] = b"stablessh";
+/// Same session protocol, but every frame starts with a tag saying whether it is zstd compressed.
+pub const ALPN_ZSTD: &[u8] = b"stablessh-zstd";
+
+const TAG_RAW: u8 = 0x00;
+const TAG_ZSTD: u8 = 0x01;
+// keystrokes and prompts are not worth the latency
+const MIN_SIZE: usize = 128;
+const MAX_BACKOFF: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Raw,
+    Zstd,
+}
+
+impl Codec {
+    pub fn alpn_protocols(enabled: bool) -> Vec<Vec<u8>> {
+        match enabled {
+            true => vec![ALPN_ZSTD.to_vec(), ALPN.to_vec()],
+            false => vec![ALPN.to_vec()],
+        }
+    }
+
+    pub fn negotiated(conn: &transport::Connection) -> Self {
+        match conn.alpn().as_deref() {
+            Some(ALPN_ZSTD) => Codec::Zstd,
+            _ => Codec::Raw,
+        }
+    }
+}
+
+/// Per-session compression state. Queued frames are kept encoded, so the codec is fixed
+/// by the first connection and every later connection of the session must agree.
+#[derive(Debug, Default)]
+pub struct Stats {
+    codec: OnceLock<Codec>,
+    raw: AtomicU64,
+    wire: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(&self, codec: Codec) -> Result<()> {
+        let bound = *self.codec.get_or_init(|| codec);
+        if bound != codec {
+            return Err(anyhow::anyhow!(
+                "compression mismatch: session uses {:?}, connection negotiated {:?}",
+                bound,
+                codec
+            ));
+        }
+        Ok(())
+    }
+
+    fn record(&self, raw: usize, wire: usize) {
+        self.raw.fetch_add(raw as u64, Ordering::Relaxed);
+        self.wire.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+
+    /// Payload bytes before compression per byte on the wire, in both directions.
+    /// None unless the session compresses.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.codec.get() != Some(&Codec::Zstd) {
+            return None;
+        }
+        let wire = self.wire.load(Ordering::Relaxed);
+        if wire == 0 {
+            return Some(1.0);
+        }
+        Some(self.raw.load(Ordering::Relaxed) as f64 / wire as f64)
+    }
+}
+
+pub struct Encoder {
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+    // frames left to send raw after compression stopped paying off
+    skip: u32,
+    backoff: u32,
+}
+
+impl Encoder {
+    pub fn new(codec: Codec, level: i32) -> Result<Self> {
+        let zstd = match codec {
+            Codec::Raw => None,
+            Codec::Zstd => Some(zstd::bulk::Compressor::new(level)?),
+        };
+        Ok(Self {
+            zstd,
+            skip: 0,
+            backoff: 1,
+        })
+    }
+
+    /// Encodes one frame. Frames that do not shrink are sent raw, and after that
+    /// compression is skipped for a growing number of frames, so already encrypted
+    /// traffic costs little CPU.
+    pub fn encode(&mut self, data: Bytes, stats: &Stats) -> Result<Bytes> {
+        let zstd = match self.zstd.as_mut() {
+            Some(zstd) => zstd,
+            None => return Ok(data),
+        };
+        let mut out = vec![0; data.len()];
+        if data.len() >= MIN_SIZE && self.skip == 0 {
+            match zstd.compress_to_buffer(&data, &mut out[1..]) {
+                Ok(n) => {
+                    self.backoff = 1;
+                    out[0] = TAG_ZSTD;
+                    out.truncate(n + 1);
+                    stats.record(data.len(), out.len());
+                    return Ok(out.into());
+                }
+                Err(_) => {
+                    self.skip = self.backoff;
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        } else if data.len() >= MIN_SIZE {
+            self.skip -= 1;
+        }
+        let mut out = BytesMut::with_capacity(data.len() + 1);
+        out.put_u8(TAG_RAW);
+        out.put_slice(&data);
+        stats.record(data.len(), out.len());
+        Ok(out.freeze())
+    }
+}
+
+pub struct Decoder {
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+    max_size: usize,
+}
+
+impl Decoder {
+    pub fn new(codec: Codec, max_size: usize) -> Result<Self> {
+        let zstd = match codec {
+            Codec::Raw => None,
+            Codec::Zstd => Some(zstd::bulk::Decompressor::new()?),
+        };
+        Ok(Self { zstd, max_size })
+    }
+
+    pub fn decode(&mut self, data: Bytes, stats: &Stats) -> Result<Bytes> {
+        let zstd = match self.zstd.as_mut() {
+            Some(zstd) => zstd,
+            None => return Ok(data),
+        };
+        let wire = data.len();
+        let out = match data.first() {
+            Some(&TAG_RAW) => data.slice(1..),
+            Some(&TAG_ZSTD) => zstd.decompress(&data[1..], self.max_size)?.into(),
+            _ => return Err(anyhow::anyhow!("invalid frame tag")),
+        };
+        stats.record(out.len(), wire);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Codec, Decoder, Encoder, Stats};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_roundtrip() {
+        let stats = Stats::new();
+        stats.bind(Codec::Zstd).unwrap();
+        let mut enc = Encoder::new(Codec::Zstd, 3).unwrap();
+        let mut dec = Decoder::new(Codec::Zstd, 4096).unwrap();
+
+        let text = Bytes::from("hello world ".repeat(100));
+        let wire = enc.encode(text.clone(), &stats).unwrap();
+        assert!(wire.len() < text.len());
+        assert_eq!(dec.decode(wire, &stats).unwrap(), text);
+
+        let short = Bytes::from_static(b"ls\n");
+        let wire = enc.encode(short.clone(), &stats).unwrap();
+        assert_eq!(wire.len(), short.len() + 1);
+        assert_eq!(dec.decode(wire, &stats).unwrap(), short);
+
+        assert!(stats.ratio().unwrap() > 1.0);
+    }
+
+    #[test]
+    fn test_incompressible() {
+        let stats = Stats::new();
+        let mut enc = Encoder::new(Codec::Zstd, 3).unwrap();
+        let mut dec = Decoder::new(Codec::Zstd, 4096).unwrap();
+        // a xorshift stream stands in for ciphertext
+        let mut x: u32 = 2463534242;
+        let noise: Vec<u8> = (0..1024)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let noise = Bytes::from(noise);
+        for _ in 0..4 {
+            let wire = enc.encode(noise.clone(), &stats).unwrap();
+            assert_eq!(wire.len(), noise.len() + 1);
+            assert_eq!(dec.decode(wire, &stats).unwrap(), noise);
+        }
+        assert!(enc.skip > 0);
+    }
+
+    #[test]
+    fn test_bind() {
+        let stats = Stats::new();
+        assert!(stats.ratio().is_none());
+        stats.bind(Codec::Raw).unwrap();
+        stats.bind(Codec::Raw).unwrap();
+        assert!(stats.bind(Codec::Zstd).is_err());
+        assert!(stats.ratio().is_none());
+
+        let mut enc = Encoder::new(Codec::Raw, 3).unwrap();
+        let data = Bytes::from_static(b"data");
+        assert_eq!(enc.encode(data.clone(), &stats).unwrap(), data);
+    }
+}
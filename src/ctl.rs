@@ -1,4 +1,4 @@
-use crate::proto_impl;
+use crate::{proto, proto_impl, utils};
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 
@@ -16,6 +16,10 @@ pub struct Opt {
 enum Targets {
     #[command(subcommand)]
     Conn(OpCmd),
+    #[command(subcommand)]
+    Limit(LimitCmd),
+    #[command(subcommand)]
+    Route(RouteCmd),
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -29,6 +33,39 @@ struct KillOpt {
     id: String,
 }
 
+#[derive(Subcommand, Debug, Clone)]
+enum LimitCmd {
+    Show,
+    Global(RateOpt),
+    Default(RateOpt),
+    Session(SessionRateOpt),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum RouteCmd {
+    Reload,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct RateOpt {
+    #[clap(value_parser = utils::parse_size)]
+    rate: u64,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct SessionRateOpt {
+    id: String,
+    #[clap(value_parser = utils::parse_size)]
+    rate: u64,
+}
+
+fn format_rate(rate: u64) -> String {
+    match rate {
+        0 => "-".to_string(),
+        rate => format!("{}/s", utils::format_size(rate)),
+    }
+}
+
 pub async fn run(opt: Opt) -> Result<()> {
     let mut client = proto_impl::CtlClient::new(&opt.ctl_target).await?;
     match opt.target {
@@ -36,13 +73,23 @@ pub async fn run(opt: Opt) -> Result<()> {
             let res = client.conn_list().await?;
             let mut t = prettytable::Table::new();
             t.set_format(*prettytable::format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
-            t.set_titles(prettytable::row!["id", "name", "last_active", "pkt_buf"]);
+            t.set_titles(prettytable::row![
+                "id",
+                "name",
+                "dest",
+                "last_active",
+                "pkt_buf",
+                "spilled",
+                "rate_limit",
+                "compression"
+            ]);
             res.conns.iter().for_each(|conn| {
                 let id = conn.id.clone();
-                let name = match conn.name.clone() {
-                    Some(name) => name,
-                    None => "".to_string(),
+                let name = match conn.legacy {
+                    true => "(legacy)".to_string(),
+                    false => conn.name.clone().unwrap_or_default(),
                 };
+                let dest = conn.dest.clone().unwrap_or_else(|| "-".to_string());
                 let last_active = match conn.last_active.clone() {
                     Some(last_active) => last_active.to_string(),
                     None => "in_use".to_string(),
@@ -51,13 +98,52 @@ pub async fn run(opt: Opt) -> Result<()> {
                     Some(pkt_buf) => pkt_buf,
                     None => 0,
                 };
-                t.add_row(prettytable::row![id, name, last_active, pkt_buf]);
+                let spilled = conn.spilled.unwrap_or_default();
+                let rate_limit = format_rate(conn.rate_limit.unwrap_or_default());
+                let compression = match conn.compression {
+                    Some(ratio) => format!("{:.2}x", ratio),
+                    None => "-".to_string(),
+                };
+                t.add_row(prettytable::row![
+                    id,
+                    name,
+                    dest,
+                    last_active,
+                    pkt_buf,
+                    spilled,
+                    rate_limit,
+                    compression
+                ]);
             });
             t.printstd();
         }
         Targets::Conn(OpCmd::Kill(kill_opt)) => {
             client.conn_kill(&kill_opt.id).await?;
         }
+        Targets::Limit(LimitCmd::Show) => {
+            let res = client.rate_limit_get().await?;
+            println!("global: {}", format_rate(res.global));
+            println!("session default: {}", format_rate(res.session_default));
+        }
+        Targets::Limit(LimitCmd::Global(rate_opt)) => {
+            client
+                .rate_limit_set(proto::RateLimitScope::Global, "", rate_opt.rate)
+                .await?;
+        }
+        Targets::Limit(LimitCmd::Default(rate_opt)) => {
+            client
+                .rate_limit_set(proto::RateLimitScope::SessionDefault, "", rate_opt.rate)
+                .await?;
+        }
+        Targets::Limit(LimitCmd::Session(rate_opt)) => {
+            client
+                .rate_limit_set(proto::RateLimitScope::Session, &rate_opt.id, rate_opt.rate)
+                .await?;
+        }
+        Targets::Route(RouteCmd::Reload) => {
+            let res = client.route_reload().await?;
+            println!("{} routes loaded", res.routes);
+        }
     }
     Ok(())
 }
@@ -0,0 +1,68 @@
+use crate::transport;
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::sync::{mpsc, watch};
+
+pub const KIND_HEARTBEAT: u8 = 0x01;
+pub const KIND_ACK: u8 = 0x02;
+pub const KIND_ENDPOINTS: u8 = 0x03;
+pub const KIND_UDP: u8 = 0x04;
+
+pub fn heartbeat() -> Bytes {
+    Bytes::from_static(&[KIND_HEARTBEAT])
+}
+
+pub fn ack(id: u32) -> Bytes {
+    let mut buf = vec![KIND_ACK];
+    buf.extend(id.to_be_bytes().iter());
+    buf.into()
+}
+
+pub fn endpoints(list: &[u8]) -> Bytes {
+    let mut buf = vec![KIND_ENDPOINTS];
+    buf.extend_from_slice(list);
+    buf.into()
+}
+
+pub fn udp(flow: u32, payload: &[u8]) -> Bytes {
+    let mut buf = Vec::with_capacity(5 + payload.len());
+    buf.push(KIND_UDP);
+    buf.extend(flow.to_be_bytes().iter());
+    buf.extend_from_slice(payload);
+    buf.into()
+}
+
+/// Routes incoming datagrams by their first byte.
+pub async fn dispatch(
+    conn: transport::Connection,
+    heartbeat: mpsc::Sender<()>,
+    ack: watch::Sender<u32>,
+    endpoints: watch::Sender<Bytes>,
+    udp: mpsc::Sender<Bytes>,
+) -> Result<()> {
+    loop {
+        let d = match conn.read_datagram().await {
+            Ok(d) => d,
+            // the stream tasks report why the connection went away
+            Err(_) => return std::future::pending().await,
+        };
+        match d.first() {
+            Some(&KIND_HEARTBEAT) => {
+                // one waiting is as good as many
+                let _ = heartbeat.try_send(());
+            }
+            Some(&KIND_ACK) if d.len() == 5 => {
+                // acks are cumulative, only the latest matters
+                let _ = ack.send(u32::from_be_bytes([d[1], d[2], d[3], d[4]]));
+            }
+            Some(&KIND_ENDPOINTS) => {
+                // the peer repeats its whole list, so an older one is of no use
+                let _ = endpoints.send(d.slice(1..));
+            }
+            Some(&KIND_UDP) if d.len() >= 5 => {
+                let _ = udp.try_send(d.slice(1..));
+            }
+            _ => log::debug!("unknown datagram: {:?}", d),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{datagram, transport};
+use anyhow::Result;
+use bytes::Bytes;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{watch, RwLock};
+
+// datagrams may be lost, so the list is repeated while the connection lasts
+const ADVERTISE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Another way to reach the server than the target the client was given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub kind: transport::Kind,
+    // host:port
+    pub addr: String,
+}
+
+impl Endpoint {
+    /// Parses `quic://host:port`, `tcp://host:port` or `ws://host:port`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let (scheme, addr) = s
+            .split_once("://")
+            .ok_or_else(|| anyhow::anyhow!("expected quic://, tcp:// or ws://: {}", s))?;
+        let kind = match scheme {
+            "quic" => transport::Kind::Quic,
+            "tcp" => transport::Kind::Tcp,
+            "ws" => transport::Kind::Ws,
+            _ => return Err(anyhow::anyhow!("unsupported endpoint scheme: {}", scheme)),
+        };
+        match addr.rsplit_once(':') {
+            Some((host, port))
+                if !host.is_empty()
+                    && port.parse::<u16>().is_ok()
+                    && !addr.contains(char::is_whitespace) => {}
+            _ => return Err(anyhow::anyhow!("expected host:port: {}", addr)),
+        }
+        Ok(Self {
+            kind,
+            addr: addr.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = match self.kind {
+            transport::Kind::Tcp => "tcp",
+            transport::Kind::Ws => "ws",
+            _ => "quic",
+        };
+        write!(f, "{}://{}", scheme, self.addr)
+    }
+}
+
+/// The list as one datagram payload, leaving out what doesn't fit in `max` bytes.
+fn encode(list: &[Endpoint], max: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for endpoint in list {
+        let s = endpoint.to_string();
+        let sep = usize::from(!buf.is_empty());
+        if buf.len() + sep + s.len() > max {
+            break;
+        }
+        if sep == 1 {
+            buf.push(b' ');
+        }
+        buf.extend_from_slice(s.as_bytes());
+    }
+    buf
+}
+
+fn decode(buf: &[u8]) -> Vec<Endpoint> {
+    String::from_utf8_lossy(buf)
+        .split_whitespace()
+        .filter_map(|s| Endpoint::parse(s).ok())
+        .collect()
+}
+
+/// The alternate endpoints of a session: the server's to advertise, or what the client
+/// has been told so far.
+#[derive(Clone)]
+pub enum Endpoints {
+    Advertise(Arc<Vec<Endpoint>>),
+    Learned(Arc<RwLock<Vec<Endpoint>>>),
+}
+
+impl Endpoints {
+    pub async fn run(
+        self,
+        conn: transport::Connection,
+        mut rx: watch::Receiver<Bytes>,
+    ) -> Result<()> {
+        match self {
+            Endpoints::Advertise(list) if !list.is_empty() => {
+                let mut ticker = tokio::time::interval(ADVERTISE_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    // the kind byte comes first
+                    let max = match conn.max_datagram_size() {
+                        Some(max) => max - 1,
+                        None => return std::future::pending().await,
+                    };
+                    let d = datagram::endpoints(&encode(&list, max));
+                    if let Err(e) = conn.send_datagram(d) {
+                        // a lost connection is reported by the stream tasks
+                        log::debug!("advertising endpoints stopped: {:?}", e);
+                        return std::future::pending().await;
+                    }
+                }
+            }
+            Endpoints::Advertise(_) => std::future::pending().await,
+            Endpoints::Learned(learned) => {
+                while rx.changed().await.is_ok() {
+                    let list = decode(&rx.borrow_and_update());
+                    if *learned.read().await != list {
+                        log::debug!("alternate endpoints: {:?}", list);
+                        *learned.write().await = list;
+                    }
+                }
+                std::future::pending().await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode, Endpoint};
+    use crate::transport::Kind;
+
+    #[test]
+    fn test_parse() {
+        let endpoint = Endpoint::parse("quic://[2001:db8::1]:2222").unwrap();
+        assert_eq!(endpoint.kind, Kind::Quic);
+        assert_eq!(endpoint.addr, "[2001:db8::1]:2222");
+        assert_eq!(endpoint.to_string(), "quic://[2001:db8::1]:2222");
+        assert_eq!(
+            Endpoint::parse("ws://example.com:443").unwrap().kind,
+            Kind::Ws
+        );
+        assert!(Endpoint::parse("example.com:2222").is_err());
+        assert!(Endpoint::parse("udp://example.com:2222").is_err());
+        assert!(Endpoint::parse("tcp://example.com").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let list: Vec<_> = [
+            "quic://192.0.2.1:2222",
+            "tcp://192.0.2.1:443",
+            "ws://example.com:443",
+        ]
+        .iter()
+        .map(|s| Endpoint::parse(s).unwrap())
+        .collect();
+        assert_eq!(decode(&encode(&list, 1200)), list);
+        assert_eq!(decode(&encode(&list, 45)), list[..2]);
+        assert_eq!(decode(b"quic://192.0.2.1:2222 bogus"), list[..1]);
+    }
+}
@@ -0,0 +1,602 @@
+use crate::utils;
+use anyhow::Result;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+pub type ReadHalf<'a> = Box<dyn AsyncRead + Send + Sync + Unpin + 'a>;
+pub type WriteHalf<'a> = Box<dyn AsyncWrite + Send + Sync + Unpin + 'a>;
+
+/// Where a session goes on the server side.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Tcp(String),
+    Unix(PathBuf),
+    // run with `sh -c`, one process per session
+    Exec(String),
+}
+
+impl Target {
+    /// Parses `host:port`, `unix:/path/to/socket` or `exec:command`.
+    pub fn parse(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Target::Unix(path.into()));
+        }
+        if let Some(command) = s.strip_prefix("exec:") {
+            if command.trim().is_empty() {
+                return Err(anyhow::anyhow!("empty command: {}", s));
+            }
+            return Ok(Target::Exec(command.to_string()));
+        }
+        Ok(Target::Tcp(s.to_string()))
+    }
+
+    pub async fn connect(&self) -> Result<Stream> {
+        let stream = match self {
+            Target::Tcp(addr) => Stream::Tcp(TcpStream::connect(addr).await?),
+            Target::Unix(path) => Stream::Unix(UnixStream::connect(path).await?),
+            Target::Exec(command) => {
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    // the session ends with the process, and the process with the session
+                    .kill_on_drop(true)
+                    .spawn()?;
+                let stdin = child.stdin.take().expect("stdin is piped");
+                let stdout = child.stdout.take().expect("stdout is piped");
+                Stream::Exec(child, stdout, stdin)
+            }
+        };
+        Ok(stream)
+    }
+
+    async fn check(&self, check: HealthCheck) -> Result<()> {
+        let mut stream = match self {
+            // a command is only run for a session
+            Target::Exec(_) => return Ok(()),
+            _ => self.connect().await?,
+        };
+        if check == HealthCheck::Ssh {
+            let (r, _) = stream.split();
+            let mut line = String::new();
+            tokio::io::BufReader::new(r).read_line(&mut line).await?;
+            if !line.starts_with("SSH-") {
+                return Err(anyhow::anyhow!("not an SSH banner: {:?}", line.trim_end()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Tcp(addr) => write!(f, "{}", addr),
+            Target::Unix(path) => write!(f, "unix:{}", path.display()),
+            Target::Exec(command) => write!(f, "exec:{}", command),
+        }
+    }
+}
+
+// a backend that takes longer than this to answer a health check is down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    // the first backend in the list that is up
+    FirstHealthy,
+    RoundRobin,
+    // the backend holding the fewest sessions, counting those whose client is away
+    LeastSessions,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum HealthCheck {
+    // backends are only left out for a session whose connect fails
+    None,
+    // the backend accepts a connection
+    Tcp,
+    // the backend greets with an SSH identification line
+    Ssh,
+}
+
+/// Raised when no backend can take a new session.
+#[derive(Debug)]
+pub struct Unavailable(String);
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no backend available: {}", self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+#[derive(Debug)]
+struct Backend {
+    target: Target,
+    healthy: AtomicBool,
+    sessions: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Inner {
+    backends: Vec<Backend>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+/// The `--forward` targets, with their health and the sessions each one holds.
+#[derive(Debug, Clone)]
+pub struct Backends(Arc<Inner>);
+
+impl Backends {
+    pub fn new(targets: &[Target], strategy: Strategy) -> Self {
+        let backends = targets
+            .iter()
+            .map(|target| Backend {
+                target: target.clone(),
+                // until a check says otherwise
+                healthy: AtomicBool::new(true),
+                sessions: AtomicUsize::new(0),
+            })
+            .collect();
+        Self(Arc::new(Inner {
+            backends,
+            strategy,
+            next: AtomicUsize::new(0),
+        }))
+    }
+
+    /// The backends in the order the strategy would try them, healthy ones first.
+    fn candidates(&self) -> Vec<usize> {
+        let inner = &self.0;
+        let len = inner.backends.len();
+        let mut order: Vec<usize> = match inner.strategy {
+            Strategy::FirstHealthy => (0..len).collect(),
+            Strategy::RoundRobin => {
+                let start = inner.next.fetch_add(1, Ordering::Relaxed);
+                (0..len).map(|i| (start + i) % len).collect()
+            }
+            Strategy::LeastSessions => {
+                let mut order: Vec<usize> = (0..len).collect();
+                order.sort_by_key(|i| inner.backends[*i].sessions.load(Ordering::Relaxed));
+                order
+            }
+        };
+        // stable, so the strategy's order holds within each group
+        order.sort_by_key(|i| !inner.backends[*i].healthy.load(Ordering::Relaxed));
+        order
+    }
+
+    /// Connects a new session to a backend, moving on to the next one when a connect fails.
+    /// The lease counts the session against its backend until dropped.
+    pub async fn connect(&self) -> Result<(Stream, Lease)> {
+        let inner = &self.0;
+        let mut errors = Vec::new();
+        for i in self.candidates() {
+            let backend = &inner.backends[i];
+            match backend.target.connect().await {
+                Ok(stream) => {
+                    backend.sessions.fetch_add(1, Ordering::Relaxed);
+                    let lease = Lease {
+                        inner: inner.clone(),
+                        index: i,
+                    };
+                    return Ok((stream, lease));
+                }
+                Err(e) => {
+                    log::warn!("Backend {} failed: {:#}", backend.target, e);
+                    errors.push(format!("{}: {:#}", backend.target, e));
+                }
+            }
+        }
+        Err(Unavailable(errors.join(", ")).into())
+    }
+
+    /// The target a session was given, for logs.
+    pub fn target(&self, lease: &Lease) -> &Target {
+        &self.0.backends[lease.index].target
+    }
+
+    /// Checks every backend each `interval`, for as long as the server runs.
+    pub async fn check_loop(self, check: HealthCheck, interval: Duration) {
+        if check == HealthCheck::None {
+            return;
+        }
+        let inner = &self.0;
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let checks: Vec<_> = (0..inner.backends.len())
+                .map(|i| tokio::spawn(check_backend(inner.clone(), i, check)))
+                .collect();
+            for check in checks {
+                let _ = check.await;
+            }
+        }
+    }
+}
+
+async fn check_backend(inner: Arc<Inner>, index: usize, check: HealthCheck) {
+    let backend = &inner.backends[index];
+    let ret = tokio::time::timeout(CHECK_TIMEOUT, backend.target.check(check)).await;
+    let ret = ret.unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+    let healthy = ret.is_ok();
+    if backend.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+        match ret {
+            Ok(()) => log::info!("Backend {} is up", backend.target),
+            Err(e) => log::warn!("Backend {} is down: {:#}", backend.target, e),
+        }
+    }
+}
+
+/// A session's hold on its backend, for `least-sessions`.
+#[derive(Debug)]
+pub struct Lease {
+    inner: Arc<Inner>,
+    index: usize,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.inner.backends[self.index]
+            .sessions
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Raised when a client asks for a destination no `--allow-dest` rule lets through.
+#[derive(Debug)]
+pub struct Denied(String);
+
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "destination not allowed: {}", self.0)
+    }
+}
+
+impl std::error::Error for Denied {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HostPattern {
+    // a host name or address, `*` matching any run of characters
+    Glob(String),
+    Cidr(IpAddr, u8),
+}
+
+/// A destination clients may ask for, a host pattern or CIDR with optional ports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    host: HostPattern,
+    // empty for any port
+    ports: Vec<u16>,
+}
+
+impl Rule {
+    /// Parses `pattern[:ports]`, e.g. `*.internal:22`, `10.0.0.0/8` or `[fd00::/8]:22,2222`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let (host, ports) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest
+                    .split_once(']')
+                    .ok_or_else(|| anyhow::anyhow!("missing ]: {}", s))?;
+                match rest {
+                    "" => (host, Vec::new()),
+                    _ if rest.starts_with(':') => (host, utils::split_ports(rest)?.1),
+                    _ => return Err(anyhow::anyhow!("invalid rule: {}", s)),
+                }
+            }
+            // more than one colon is a bare IPv6 address or network, any port
+            None if s.matches(':').count() > 1 => (s, Vec::new()),
+            None if s.contains(':') => utils::split_ports(s)?,
+            None => (s, Vec::new()),
+        };
+        if host.is_empty() {
+            return Err(anyhow::anyhow!("empty host: {}", s));
+        }
+        let host = match host.split_once('/') {
+            Some((ip, len)) => {
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid network: {}", host))?;
+                let max = if ip.is_ipv4() { 32 } else { 128 };
+                let len: u8 = match len.parse() {
+                    Ok(len) if len <= max => len,
+                    _ => return Err(anyhow::anyhow!("invalid prefix length: {}", host)),
+                };
+                HostPattern::Cidr(ip, len)
+            }
+            None => HostPattern::Glob(host.to_ascii_lowercase()),
+        };
+        Ok(Self { host, ports })
+    }
+
+    fn matches(&self, name: &str, addr: SocketAddr) -> bool {
+        if !self.ports.is_empty() && !self.ports.contains(&addr.port()) {
+            return false;
+        }
+        match &self.host {
+            HostPattern::Glob(pattern) => {
+                glob(pattern.as_bytes(), name.to_ascii_lowercase().as_bytes())
+            }
+            HostPattern::Cidr(net, len) => {
+                in_network(utils::unmap(addr.ip()), utils::unmap(*net), *len)
+            }
+        }
+    }
+}
+
+/// Matches `s` against `pattern`, where `*` matches any run of characters. On a mismatch
+/// only the last `*` seen takes one more character, which keeps it O(n·m).
+pub fn glob(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // where the last `*` was, and where in `s` its run ends
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((star_p, star_i)) => {
+                    star = Some((star_p, star_i + 1));
+                    p = star_p + 1;
+                    i = star_i + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn in_network(ip: IpAddr, net: IpAddr, len: u8) -> bool {
+    let (ip, net, bits) = match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => (u32::from(ip) as u128, u32::from(net) as u128, 32),
+        (IpAddr::V6(ip), IpAddr::V6(net)) => (u128::from(ip), u128::from(net), 128),
+        _ => return false,
+    };
+    let shift = bits - u32::from(len);
+    shift >= bits || ip >> shift == net >> shift
+}
+
+/// Connects to a destination the client asked for, `host:port`. The name is resolved here
+/// and only the addresses a rule allows are tried, so a name can't lead outside the list.
+pub async fn connect_dest(rules: &[Rule], dest: &str) -> Result<Stream> {
+    let (host, port) = dest
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| anyhow::anyhow!("expected host:port: {}", dest))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let allowed: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await?
+        .filter(|addr| rules.iter().any(|rule| rule.matches(host, *addr)))
+        .collect();
+    if allowed.is_empty() {
+        return Err(Denied(dest.to_string()).into());
+    }
+    Ok(Stream::Tcp(TcpStream::connect(&allowed[..]).await?))
+}
+
+/// The backend end of a session, kept in the pool while the client is away.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Exec(Child, ChildStdout, ChildStdin),
+    // a session that only carries UDP flows has no stream
+    Idle,
+}
+
+impl Stream {
+    /// Whether `--proxy-protocol` applies. A command such as `sshd -i` or a Unix socket
+    /// would take the header for the start of the SSH stream.
+    pub fn takes_proxy_header(&self) -> bool {
+        matches!(self, Stream::Tcp(_))
+    }
+
+    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        match self {
+            Stream::Tcp(tcp) => {
+                let (r, w) = tcp.split();
+                (Box::new(r), Box::new(w))
+            }
+            Stream::Unix(unix) => {
+                let (r, w) = unix.split();
+                (Box::new(r), Box::new(w))
+            }
+            Stream::Exec(_, stdout, stdin) => (Box::new(stdout), Box::new(stdin)),
+            Stream::Idle => (Box::new(crate::udp::Idle), Box::new(tokio::io::sink())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backends, HealthCheck, Rule, Strategy, Target};
+    use std::sync::atomic::Ordering;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Target::parse("localhost:22").unwrap(),
+            Target::Tcp("localhost:22".to_string())
+        );
+        assert_eq!(
+            Target::parse("unix:/run/sshd.sock").unwrap(),
+            Target::Unix("/run/sshd.sock".into())
+        );
+        assert_eq!(
+            Target::parse("exec:sshd -i").unwrap(),
+            Target::Exec("sshd -i".to_string())
+        );
+        assert!(Target::parse("exec: ").is_err());
+        assert_eq!(
+            Target::parse("exec:sshd -i").unwrap().to_string(),
+            "exec:sshd -i"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec() {
+        let mut stream = Target::Exec("printf '>'; cat".to_string())
+            .connect()
+            .await
+            .unwrap();
+        let (mut r, mut w) = stream.split();
+        w.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 6];
+        r.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b">hello");
+    }
+
+    #[test]
+    fn test_rule() {
+        let rule = Rule::parse("*.internal:22").unwrap();
+        assert!(rule.matches("db.internal", "10.0.0.1:22".parse().unwrap()));
+        assert!(rule.matches("DB.Internal", "10.0.0.1:22".parse().unwrap()));
+        assert!(!rule.matches("db.internal", "10.0.0.1:2222".parse().unwrap()));
+        assert!(!rule.matches("internal.example", "10.0.0.1:22".parse().unwrap()));
+
+        let rule = Rule::parse("10.0.0.0/8").unwrap();
+        assert!(rule.matches("anything", "10.1.2.3:5432".parse().unwrap()));
+        assert!(rule.matches("anything", "[::ffff:10.1.2.3]:22".parse().unwrap()));
+        assert!(!rule.matches("anything", "192.0.2.1:22".parse().unwrap()));
+
+        let rule = Rule::parse("[fd00::/8]:22,2222").unwrap();
+        assert!(rule.matches("host", "[fd12::1]:2222".parse().unwrap()));
+        assert!(!rule.matches("host", "[fe80::1]:22".parse().unwrap()));
+        assert!(Rule::parse("fd00::/8")
+            .unwrap()
+            .matches("host", "[fd12::1]:1".parse().unwrap()));
+        assert!(Rule::parse("0.0.0.0/0")
+            .unwrap()
+            .matches("host", "192.0.2.1:1".parse().unwrap()));
+
+        assert!(Rule::parse("10.0.0.0/33").is_err());
+        assert!(Rule::parse("[fd00::/8").is_err());
+        assert!(Rule::parse(":22").is_err());
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(super::glob(b"*.internal", b"db.internal"));
+        assert!(super::glob(b"*", b""));
+        assert!(super::glob(b"a*b*c", b"aXbYbc"));
+        assert!(super::glob(b"db*", b"db"));
+        assert!(!super::glob(b"*.internal", b"internal"));
+        assert!(!super::glob(b"a*b", b"aXbc"));
+        assert!(!super::glob(b"", b"a"));
+        // exponential for a backtracking matcher
+        let pattern = "*a".repeat(20) + "b";
+        assert!(!super::glob(pattern.as_bytes(), "a".repeat(200).as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn test_connect_dest() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest = listener.local_addr().unwrap().to_string();
+        let rules = [Rule::parse("127.0.0.0/8").unwrap()];
+        assert!(super::connect_dest(&rules, &dest).await.is_ok());
+        let rules = [Rule::parse("*.internal").unwrap()];
+        let e = match super::connect_dest(&rules, &dest).await {
+            Ok(_) => panic!("connected to {}", dest),
+            Err(e) => e,
+        };
+        assert!(e.downcast_ref::<super::Denied>().is_some());
+    }
+
+    async fn listeners(n: usize) -> (Vec<tokio::net::TcpListener>, Vec<Target>) {
+        let mut listeners = Vec::new();
+        let mut targets = Vec::new();
+        for _ in 0..n {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            targets.push(Target::Tcp(listener.local_addr().unwrap().to_string()));
+            listeners.push(listener);
+        }
+        (listeners, targets)
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let (mut listeners, targets) = listeners(3).await;
+        // the first backend is gone
+        drop(listeners.remove(0));
+        let backends = Backends::new(&targets, Strategy::FirstHealthy);
+        let (_, lease) = backends.connect().await.unwrap();
+        assert_eq!(backends.target(&lease), &targets[1]);
+
+        // a backend found down is tried last
+        backends.0.backends[1]
+            .healthy
+            .store(false, Ordering::Relaxed);
+        assert_eq!(backends.candidates(), [0, 2, 1]);
+
+        drop(listeners);
+        let e = backends.connect().await.unwrap_err();
+        assert!(e.downcast_ref::<super::Unavailable>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_strategy() {
+        let (_listeners, targets) = listeners(3).await;
+        let backends = Backends::new(&targets, Strategy::RoundRobin);
+        assert_eq!(backends.candidates(), [0, 1, 2]);
+        assert_eq!(backends.candidates(), [1, 2, 0]);
+
+        let backends = Backends::new(&targets, Strategy::LeastSessions);
+        let (_, first) = backends.connect().await.unwrap();
+        let (_, second) = backends.connect().await.unwrap();
+        assert_eq!(backends.target(&first), &targets[0]);
+        assert_eq!(backends.target(&second), &targets[1]);
+        // a session that ends frees its backend
+        drop(first);
+        let (_, third) = backends.connect().await.unwrap();
+        assert_eq!(backends.target(&third), &targets[0]);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let (listeners, targets) = listeners(1).await;
+        let target = &targets[0];
+        let accept = tokio::spawn(async move {
+            let (mut tcp, _) = listeners[0].accept().await.unwrap();
+            tcp.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").await.unwrap();
+            listeners
+        });
+        assert!(target.check(HealthCheck::Ssh).await.is_ok());
+        let listeners = accept.await.unwrap();
+        let accept = tokio::spawn(async move {
+            let (mut tcp, _) = listeners[0].accept().await.unwrap();
+            tcp.write_all(b"HTTP/1.1 400 Bad Request\r\n")
+                .await
+                .unwrap();
+            listeners
+        });
+        assert!(target.check(HealthCheck::Ssh).await.is_err());
+        let _listeners = accept.await.unwrap();
+        // accepted by the kernel, nobody needs to answer
+        assert!(target.check(HealthCheck::Tcp).await.is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{datagram, transport};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::{sync::mpsc, time::Instant};
+
+/// Raised when the peer stopped answering heartbeats, so the path is considered dead.
+#[derive(Debug)]
+pub struct Timeout;
+
+impl std::fmt::Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "heartbeat timeout")
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    interval: Duration,
+    misses: u32,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, misses: u32) -> Self {
+        Self { interval, misses }
+    }
+
+    /// How long the peer may stay silent before the path is declared dead.
+    /// A slow path gets more slack, so heartbeats still in flight are not counted as missed.
+    pub fn deadline(&self, rtt: Duration) -> Duration {
+        self.interval.max(rtt * 2) * self.misses.max(1)
+    }
+
+    /// Sends heartbeats as datagrams and watches for the peer's.
+    /// Detection is armed by the first heartbeat received, so a peer with heartbeats
+    /// disabled never gets dropped.
+    pub async fn run(
+        self,
+        conn: transport::Connection,
+        mut seen: mpsc::Receiver<()>,
+    ) -> Result<()> {
+        if self.interval.is_zero() {
+            return std::future::pending().await;
+        }
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut last_seen: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if let Some(last_seen) = last_seen {
+                        let deadline = self.deadline(conn.rtt());
+                        if last_seen.elapsed() > deadline {
+                            log::debug!("heartbeat: no answer for {:?}", last_seen.elapsed());
+                            conn.close(b"heartbeat timeout");
+                            return Err(Timeout.into());
+                        }
+                    }
+                    match conn.send_datagram(datagram::heartbeat()) {
+                        Ok(_) => {}
+                        // a lost connection is reported by the stream tasks
+                        Err(e) => {
+                            log::debug!("heartbeat stopped: {:?}", e);
+                            return std::future::pending().await;
+                        }
+                    }
+                }
+                Some(_) = seen.recv() => {
+                    last_seen = Some(Instant::now());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    #[test]
+    fn test_deadline() {
+        let hb = super::Heartbeat::new(Duration::from_millis(250), 3);
+        assert_eq!(
+            hb.deadline(Duration::from_millis(20)),
+            Duration::from_millis(750)
+        );
+        assert_eq!(
+            hb.deadline(Duration::from_millis(200)),
+            Duration::from_millis(1200)
+        );
+        let hb = super::Heartbeat::new(Duration::from_millis(250), 0);
+        assert_eq!(
+            hb.deadline(Duration::from_millis(20)),
+            Duration::from_millis(250)
+        );
+    }
+}
@@ -0,0 +1,454 @@
+use crate::utils;
+use anyhow::Result;
+use clap::Parser;
+use quinn_proto::{ConnectionId, ConnectionIdGenerator};
+use ring::rand::SecureRandom;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, sync::Notify};
+
+/// Length of the connection IDs we issue. Short headers don't carry it, so `lb` assumes it.
+pub const CID_LEN: usize = 8;
+// the top three bits of the first octet, the config rotation of QUIC-LB
+const SERVER_CONFIG: u8 = 0b000;
+// the QUIC-LB value for "not routable", client IDs are never routed on
+const CLIENT_CONFIG: u8 = 0b111;
+const KEY_LEN: usize = 4;
+// a flow whose server sent nothing for this long is dropped
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+// sockets the balancer holds at most, the flow quiet for longest makes room for a new one
+const MAX_FLOWS: usize = 4096;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(name = "lb")]
+pub struct Opt {
+    #[clap(long = "listen", short = 'l', default_value = "[::]:2222")]
+    listen: SocketAddr,
+
+    // ID=ADDR of a server started with --server-id ID, once per server
+    #[clap(long = "backend", short = 'b', value_parser = parse_backend, required = true)]
+    backends: Vec<(u16, SocketAddr)>,
+}
+
+fn parse_backend(s: &str) -> Result<(u16, SocketAddr)> {
+    let (id, addr) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("expected ID=ADDR: {}", s))?;
+    let addr = utils::resolve(addr, false, false)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("could not resolve {}", addr))?;
+    Ok((id.parse()?, addr))
+}
+
+fn first_octet(config: u8) -> u8 {
+    // the rest of the octet encodes the length, as QUIC-LB allows
+    config << 5 | (CID_LEN as u8 - 1)
+}
+
+fn fill_random(buf: &mut [u8]) {
+    ring::rand::SystemRandom::new()
+        .fill(buf)
+        .expect("no system random source");
+}
+
+/// Issues connection IDs carrying the server ID in plaintext, QUIC-LB style, so `lb` sends
+/// the packets of a connection to its server whatever address they come from.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerIdGenerator {
+    server_id: u16,
+}
+
+impl ServerIdGenerator {
+    pub fn new(server_id: u16) -> Self {
+        Self { server_id }
+    }
+}
+
+impl ConnectionIdGenerator for ServerIdGenerator {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut cid = [0; CID_LEN];
+        cid[0] = first_octet(SERVER_CONFIG);
+        cid[1..3].copy_from_slice(&self.server_id.to_be_bytes());
+        fill_random(&mut cid[3..]);
+        ConnectionId::new(&cid)
+    }
+
+    fn cid_len(&self) -> usize {
+        CID_LEN
+    }
+
+    fn cid_lifetime(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Issues the client's connection IDs with a prefix derived from its key. A new connection
+/// is only known by the IDs in its Initial, so this is what pins a client's reconnects.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientKeyGenerator {
+    key: [u8; KEY_LEN],
+}
+
+impl ClientKeyGenerator {
+    pub fn new(pubkey: &[u8]) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, pubkey);
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&digest.as_ref()[..KEY_LEN]);
+        Self { key }
+    }
+}
+
+impl ConnectionIdGenerator for ClientKeyGenerator {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut cid = [0; CID_LEN];
+        cid[0] = first_octet(CLIENT_CONFIG);
+        cid[1..1 + KEY_LEN].copy_from_slice(&self.key);
+        fill_random(&mut cid[1 + KEY_LEN..]);
+        ConnectionId::new(&cid)
+    }
+
+    fn cid_len(&self) -> usize {
+        CID_LEN
+    }
+
+    fn cid_lifetime(&self) -> Option<Duration> {
+        None
+    }
+}
+
+pub fn endpoint_config<G>(generator: G) -> quinn::EndpointConfig
+where
+    G: ConnectionIdGenerator + Copy + Sync + 'static,
+{
+    let mut config = quinn::EndpointConfig::default();
+    config.cid_generator(move || Box::new(generator));
+    config
+}
+
+/// The destination connection ID of a packet, and the source one if it has a long header.
+fn parse_cids(pkt: &[u8]) -> Option<(&[u8], Option<&[u8]>)> {
+    let first = *pkt.first()?;
+    if first & 0x80 == 0 {
+        return Some((pkt.get(1..1 + CID_LEN)?, None));
+    }
+    let dcid_len = *pkt.get(5)? as usize;
+    let dcid = pkt.get(6..6 + dcid_len)?;
+    let scid_len = *pkt.get(6 + dcid_len)? as usize;
+    let scid = pkt.get(7 + dcid_len..7 + dcid_len + scid_len)?;
+    Some((dcid, Some(scid)))
+}
+
+fn server_id(cid: &[u8]) -> Option<u16> {
+    if cid.len() != CID_LEN || cid[0] >> 5 != SERVER_CONFIG {
+        return None;
+    }
+    Some(u16::from_be_bytes([cid[1], cid[2]]))
+}
+
+fn client_key(cid: &[u8]) -> Option<&[u8]> {
+    if cid.len() != CID_LEN || cid[0] >> 5 != CLIENT_CONFIG {
+        return None;
+    }
+    Some(&cid[1..1 + KEY_LEN])
+}
+
+struct Backends {
+    servers: HashMap<u16, SocketAddr>,
+}
+
+impl Backends {
+    /// Where a packet goes: to the server named in its connection ID, or, for a connection
+    /// the servers haven't issued IDs for yet, to the one the client key hashes to.
+    fn route(&self, pkt: &[u8]) -> Option<(u16, SocketAddr)> {
+        let (dcid, scid) = parse_cids(pkt)?;
+        if let Some(id) = server_id(dcid) {
+            if let Some(addr) = self.servers.get(&id) {
+                return Some((id, *addr));
+            }
+        }
+        let key = scid.and_then(client_key).unwrap_or(dcid);
+        self.pick(key)
+    }
+
+    /// Rendezvous hashing: adding or removing a server only moves the keys that hash to it.
+    fn pick(&self, key: &[u8]) -> Option<(u16, SocketAddr)> {
+        self.servers
+            .iter()
+            .max_by_key(|(id, _)| {
+                let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+                ctx.update(&id.to_be_bytes());
+                ctx.update(key);
+                let digest = ctx.finish();
+                <[u8; 8]>::try_from(&digest.as_ref()[..8]).unwrap()
+            })
+            .map(|(id, addr)| (*id, *addr))
+    }
+}
+
+/// A client address talking to a server, through a socket of its own.
+struct Flow {
+    upstream: UdpSocket,
+    last: Mutex<Instant>,
+    // stops the reply loop, and with it the socket
+    evicted: Notify,
+}
+
+impl Flow {
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.last.lock().unwrap()
+    }
+}
+
+struct Flows {
+    map: HashMap<(SocketAddr, u16), Arc<Flow>>,
+    max: usize,
+}
+
+impl Flows {
+    fn new(max: usize) -> Self {
+        Self {
+            map: HashMap::new(),
+            max,
+        }
+    }
+
+    fn insert(&mut self, key: (SocketAddr, u16), flow: Arc<Flow>) {
+        if self.map.len() >= self.max {
+            let oldest = self
+                .map
+                .iter()
+                .min_by_key(|(_, flow)| flow.last())
+                .map(|(key, _)| *key);
+            if let Some((client, id)) = oldest {
+                log::debug!("lb: too many flows, dropping {} to server {}", client, id);
+                if let Some(flow) = self.map.remove(&(client, id)) {
+                    flow.evicted.notify_one();
+                }
+            }
+        }
+        self.map.insert(key, flow);
+    }
+
+    /// Forgets `flow`, unless it was replaced already.
+    fn remove(&mut self, key: (SocketAddr, u16), flow: &Arc<Flow>) {
+        if self.map.get(&key).is_some_and(|f| Arc::ptr_eq(f, flow)) {
+            self.map.remove(&key);
+        }
+    }
+}
+
+pub async fn run(opt: Opt) -> Result<()> {
+    let backends = Backends {
+        servers: opt.backends.iter().copied().collect(),
+    };
+    let socket = Arc::new(UdpSocket::bind(opt.listen).await?);
+    tokio::select! {
+        ret = forward_loop(socket, backends) => ret?,
+        _ = utils::stop_signal_wait() => {}
+    }
+    Ok(())
+}
+
+async fn open(addr: SocketAddr) -> Result<UdpSocket> {
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let upstream = UdpSocket::bind(bind).await?;
+    upstream.connect(addr).await?;
+    Ok(upstream)
+}
+
+/// Every client address gets its own socket towards each server it talks to, so replies
+/// find their way back. The servers see the balancer's address, not the client's.
+async fn forward_loop(socket: Arc<UdpSocket>, backends: Backends) -> Result<()> {
+    let flows = Arc::new(Mutex::new(Flows::new(MAX_FLOWS)));
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(v) => v,
+            Err(e) => {
+                log::debug!("lb: recv failed: {:?}", e);
+                continue;
+            }
+        };
+        let (id, addr) = match backends.route(&buf[..len]) {
+            Some(v) => v,
+            None => continue,
+        };
+        // only this loop adds flows, so nobody else can open this one meanwhile
+        let flow = flows.lock().unwrap().map.get(&(from, id)).cloned();
+        let flow = match flow {
+            Some(flow) => flow,
+            None => {
+                let upstream = match open(addr).await {
+                    Ok(upstream) => upstream,
+                    Err(e) => {
+                        log::warn!("lb: no socket to server {} ({}): {:#}", id, addr, e);
+                        continue;
+                    }
+                };
+                log::debug!("lb: new flow {} to server {} ({})", from, id, addr);
+                let flow = Arc::new(Flow {
+                    upstream,
+                    last: Mutex::new(Instant::now()),
+                    evicted: Notify::new(),
+                });
+                flows.lock().unwrap().insert((from, id), flow.clone());
+                tokio::spawn(reply_loop(
+                    socket.clone(),
+                    flow.clone(),
+                    from,
+                    flows.clone(),
+                    id,
+                ));
+                flow
+            }
+        };
+        flow.touch();
+        if let Err(e) = flow.upstream.send(&buf[..len]).await {
+            log::debug!("lb: send to server {} failed: {:?}", id, e);
+        }
+    }
+}
+
+async fn reply_loop(
+    socket: Arc<UdpSocket>,
+    flow: Arc<Flow>,
+    client: SocketAddr,
+    flows: Arc<Mutex<Flows>>,
+    id: u16,
+) {
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        let recv = tokio::time::timeout(FLOW_TIMEOUT, flow.upstream.recv(&mut buf));
+        let ret = tokio::select! {
+            ret = recv => ret,
+            _ = flow.evicted.notified() => break,
+        };
+        match ret {
+            Ok(Ok(len)) => {
+                if let Err(e) = socket.send_to(&buf[..len], client).await {
+                    log::debug!("lb: send to {} failed: {:?}", client, e);
+                }
+            }
+            Ok(Err(e)) => {
+                log::debug!("lb: server {} unreachable: {:?}", id, e);
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+    log::debug!("lb: flow {} to server {} closed", client, id);
+    flows.lock().unwrap().remove((client, id), &flow);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backends, ClientKeyGenerator, Flow, Flows, ServerIdGenerator, CID_LEN};
+    use quinn_proto::ConnectionIdGenerator;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+    use tokio::{net::UdpSocket, sync::Notify};
+
+    fn initial(dcid: &[u8], scid: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0xc0, 0, 0, 0, 1, dcid.len() as u8];
+        pkt.extend_from_slice(dcid);
+        pkt.push(scid.len() as u8);
+        pkt.extend_from_slice(scid);
+        pkt.extend_from_slice(&[0; 32]);
+        pkt
+    }
+
+    fn short(dcid: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0x40];
+        pkt.extend_from_slice(dcid);
+        pkt.extend_from_slice(&[0; 32]);
+        pkt
+    }
+
+    fn backends(ids: &[u16]) -> Backends {
+        let servers: HashMap<_, _> = ids
+            .iter()
+            .map(|id| (*id, format!("127.0.0.1:{}", 2000 + id).parse().unwrap()))
+            .collect();
+        Backends { servers }
+    }
+
+    #[test]
+    fn test_route_by_server_id() {
+        let lb = backends(&[1, 2, 3]);
+        let cid = ServerIdGenerator::new(2).generate_cid();
+        assert_eq!(cid.len(), CID_LEN);
+        assert_eq!(lb.route(&short(&cid)).unwrap().0, 2);
+        let client = ClientKeyGenerator::new(b"client").generate_cid();
+        assert_eq!(lb.route(&initial(&cid, &client)).unwrap().0, 2);
+        assert!(lb.route(&[0x40, 1, 2]).is_none());
+    }
+
+    #[test]
+    fn test_route_by_client_key() {
+        let lb = backends(&[1, 2, 3, 4]);
+        let mut picked = std::collections::HashSet::new();
+        for i in 0..32_u8 {
+            let mut generator = ClientKeyGenerator::new(&[i]);
+            let id = lb
+                .route(&initial(&[i; 8], &generator.generate_cid()))
+                .unwrap()
+                .0;
+            // every new connection of the client lands on the same server
+            for _ in 0..8 {
+                let dcid = ServerIdGenerator::new(9).generate_cid();
+                let pkt = initial(&dcid, &generator.generate_cid());
+                assert_eq!(lb.route(&pkt).unwrap().0, id);
+            }
+            // and stays there when another server goes away
+            let rest: Vec<u16> = [1, 2, 3, 4]
+                .into_iter()
+                .filter(|n| *n != id % 4 + 1)
+                .collect();
+            let others = backends(&rest);
+            let pkt = initial(&[0; 8], &generator.generate_cid());
+            assert_eq!(others.route(&pkt).unwrap().0, id);
+            picked.insert(id);
+        }
+        assert_eq!(picked.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_max_flows() {
+        let client = "127.0.0.1:1000".parse().unwrap();
+        let mut flows = Flows::new(2);
+        let mut opened = vec![];
+        for id in 0..3 {
+            let flow = Arc::new(Flow {
+                upstream: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                last: Mutex::new(Instant::now() + Duration::from_secs(id.into())),
+                evicted: Notify::new(),
+            });
+            flows.insert((client, id), flow.clone());
+            opened.push(flow);
+        }
+        // the quietest flow went, and its reply loop is told to stop
+        assert_eq!(flows.map.len(), 2);
+        assert!(!flows.map.contains_key(&(client, 0)));
+        opened[0].evicted.notified().await;
+        // a stale flow doesn't take its replacement along
+        flows.remove((client, 1), &opened[0]);
+        assert!(flows.map.contains_key(&(client, 1)));
+        flows.remove((client, 1), &opened[1]);
+        assert!(!flows.map.contains_key(&(client, 1)));
+    }
+}
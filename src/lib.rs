@@ -1,11 +1,30 @@
 pub mod proto {
     tonic::include_proto!("stablessh");
 }
+pub mod ack;
 pub mod client;
+pub mod compress;
 pub mod ctl;
+pub mod datagram;
+pub mod endpoints;
+pub mod forward;
+pub mod heartbeat;
+pub mod http_proxy;
+pub mod lb;
+pub mod mux;
 pub mod pkt_buf;
 pub mod pool;
 pub mod proto_impl;
+pub mod proxy_protocol;
 pub mod queue;
+pub mod quic;
+pub mod ratelimit;
+pub mod relay;
+pub mod route;
 pub mod server;
+pub mod socks5;
+pub mod spill;
+pub mod transport;
+pub mod udp;
 pub mod utils;
+pub mod ws;
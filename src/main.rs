@@ -1,5 +1,5 @@
 use clap::{Parser, Subcommand};
-use stablessh::{client, ctl, server};
+use stablessh::{client, ctl, lb, relay, server};
 
 #[derive(Parser, Debug)]
 struct Cli {
@@ -9,9 +9,11 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    Server(server::Opt),
-    Client(client::Opt),
+    Server(Box<server::Opt>),
+    Client(Box<client::Opt>),
     Ctl(ctl::Opt),
+    Relay(relay::Opt),
+    Lb(lb::Opt),
 }
 
 #[tokio::main]
@@ -20,11 +22,11 @@ async fn main() {
 
     let args = Cli::parse();
     match args.command {
-        Commands::Server(opt) => match server::run(opt).await {
+        Commands::Server(opt) => match server::run(*opt).await {
             Ok(_) => {}
             Err(e) => log::error!("{:?}", e),
         },
-        Commands::Client(opt) => match client::run(opt).await {
+        Commands::Client(opt) => match client::run(*opt).await {
             Ok(_) => {}
             Err(e) => log::error!("{:?}", e),
         },
@@ -32,6 +34,14 @@ async fn main() {
             Ok(_) => {}
             Err(e) => log::error!("{:?}", e),
         },
+        Commands::Relay(opt) => match relay::run(opt).await {
+            Ok(_) => {}
+            Err(e) => log::error!("{:?}", e),
+        },
+        Commands::Lb(opt) => match lb::run(opt).await {
+            Ok(_) => {}
+            Err(e) => log::error!("{:?}", e),
+        },
     }
     std::process::exit(0);
 }
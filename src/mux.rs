@@ -0,0 +1,640 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch, Mutex, Semaphore},
+};
+
+const KIND_OPEN: u8 = 0x00;
+const KIND_DATA: u8 = 0x01;
+const KIND_FIN: u8 = 0x02;
+const KIND_WINDOW: u8 = 0x03;
+const KIND_DATAGRAM: u8 = 0x04;
+const KIND_CLOSE: u8 = 0x05;
+
+// stream id u32, kind u8, length u16
+const HEADER_SIZE: usize = 7;
+// so a whole frame fits in 64 KiB
+const MAX_PAYLOAD: usize = u16::MAX as usize - HEADER_SIZE;
+const MAX_FRAME: usize = 32768;
+/// Bytes a stream may have in flight before the reader consumes them, so a slow
+/// stream can't stall the others sharing the connection.
+const WINDOW: usize = 1 << 20;
+/// What a data frame costs against the window on top of its payload, so a window's worth
+/// of tiny frames can't hold much more memory than the window itself.
+const FRAME_COST: usize = 64;
+// the most data frames a stream can have waiting, and its end
+const STREAM_QUEUE: usize = WINDOW / (FRAME_COST + 1) + 1;
+/// Streams open at once, a session only needs one.
+const MAX_STREAMS: usize = 64;
+// datagrams waiting to be read, or written, newer ones are dropped
+const DATAGRAM_QUEUE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The peer, or this side, closed the connection on purpose.
+    Closed,
+    /// The peer closed the connection, giving a reason.
+    ClosedWith(String),
+    /// The underlying byte stream broke.
+    Lost(String),
+    /// A payload doesn't fit in a frame.
+    TooLarge(usize),
+    /// Datagrams are written slower than they are sent, so this one was dropped.
+    Full,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Closed => write!(f, "connection closed"),
+            Error::ClosedWith(reason) => write!(f, "connection closed: {}", reason),
+            Error::Lost(reason) => write!(f, "connection lost: {}", reason),
+            Error::TooLarge(len) => write!(f, "{} bytes don't fit in a frame", len),
+            Error::Full => write!(f, "datagram queue full"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+type Frame = (Bytes, Bytes);
+
+fn frame(id: u32, kind: u8, payload: Bytes) -> Result<Frame, Error> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(Error::TooLarge(payload.len()));
+    }
+    let mut header = BytesMut::with_capacity(HEADER_SIZE);
+    header.put_u32(id);
+    header.put_u8(kind);
+    header.put_u16(payload.len() as u16);
+    Ok((header.freeze(), payload))
+}
+
+fn violation(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+struct StreamState {
+    // an empty chunk marks the end of the stream
+    data: Option<mpsc::Sender<Bytes>>,
+    credit: Arc<Semaphore>,
+    // what the peer may still send before we grant it more
+    window: usize,
+    recv_dropped: bool,
+}
+
+/// State shared by the handles, the streams and the I/O tasks.
+struct Core {
+    // None tells the writer to stop
+    out: mpsc::UnboundedSender<Option<Frame>>,
+    // datagrams get their own queue, as nothing holds them back like a stream's window
+    datagrams: mpsc::Sender<Frame>,
+    streams: std::sync::Mutex<HashMap<u32, StreamState>>,
+    error: std::sync::Mutex<Option<Error>>,
+    failed: watch::Sender<bool>,
+    next_id: AtomicU32,
+}
+
+impl Core {
+    fn error(&self) -> Error {
+        self.error
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| Error::Lost("writer stopped".to_string()))
+    }
+
+    fn send(&self, id: u32, kind: u8, payload: Bytes) -> Result<(), Error> {
+        let frame = frame(id, kind, payload)?;
+        if let Some(e) = self.error.lock().unwrap().clone() {
+            return Err(e);
+        }
+        self.out.send(Some(frame)).map_err(|_| self.error())
+    }
+
+    fn send_datagram(&self, payload: Bytes) -> Result<(), Error> {
+        let frame = frame(0, KIND_DATAGRAM, payload)?;
+        if let Some(e) = self.error.lock().unwrap().clone() {
+            return Err(e);
+        }
+        self.datagrams.try_send(frame).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => Error::Full,
+            mpsc::error::TrySendError::Closed(_) => self.error(),
+        })
+    }
+
+    /// Ends the connection: pending and future operations fail with `e`.
+    fn fail(&self, e: Error) {
+        {
+            let mut error = self.error.lock().unwrap();
+            if error.is_some() {
+                return;
+            }
+            *error = Some(e);
+        }
+        for (_, state) in self.streams.lock().unwrap().drain() {
+            state.credit.close();
+        }
+        let _ = self.out.send(None);
+        let _ = self.failed.send(true);
+    }
+
+    fn register(self: &Arc<Self>, id: u32) -> (SendStream, RecvStream) {
+        let (tx, rx) = mpsc::channel(STREAM_QUEUE);
+        let credit = Arc::new(Semaphore::new(WINDOW));
+        self.streams.lock().unwrap().insert(
+            id,
+            StreamState {
+                data: Some(tx),
+                credit: credit.clone(),
+                window: WINDOW,
+                recv_dropped: false,
+            },
+        );
+        let send = SendStream {
+            id,
+            credit,
+            core: self.clone(),
+            finished: false,
+        };
+        let recv = RecvStream {
+            id,
+            rx,
+            core: self.clone(),
+            eof: false,
+        };
+        (send, recv)
+    }
+}
+
+struct Inner {
+    core: Arc<Core>,
+    accept: Mutex<mpsc::Receiver<(SendStream, RecvStream)>>,
+    datagrams: Mutex<mpsc::Receiver<Bytes>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let _ = self.core.send(0, KIND_CLOSE, Bytes::new());
+        self.core.fail(Error::Closed);
+    }
+}
+
+/// Streams and datagrams multiplexed over one reliable byte stream, such as TLS over TCP,
+/// with the subset of `quinn::Connection` the session protocol needs.
+/// Dropping the last handle closes the connection.
+#[derive(Clone)]
+pub struct Connection {
+    inner: Arc<Inner>,
+}
+
+impl Connection {
+    pub fn new<T: AsyncRead + AsyncWrite + Send + 'static>(io: T, client: bool) -> Self {
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let (datagrams_out_tx, datagrams_out_rx) = mpsc::channel(DATAGRAM_QUEUE);
+        let (accept_tx, accept_rx) = mpsc::channel(MAX_STREAMS);
+        let (datagram_tx, datagram_rx) = mpsc::channel(DATAGRAM_QUEUE);
+        let (failed, _) = watch::channel(false);
+        let core = Arc::new(Core {
+            out: out_tx,
+            datagrams: datagrams_out_tx,
+            streams: std::sync::Mutex::new(HashMap::new()),
+            error: std::sync::Mutex::new(None),
+            failed,
+            // the two sides never pick the same id
+            next_id: AtomicU32::new(if client { 1 } else { 2 }),
+        });
+        let (read, write) = tokio::io::split(io);
+        tokio::spawn(write_loop(core.clone(), write, out_rx, datagrams_out_rx));
+        tokio::spawn(read_loop(
+            core.clone(),
+            read,
+            client,
+            accept_tx,
+            datagram_tx,
+        ));
+        Self {
+            inner: Arc::new(Inner {
+                core,
+                accept: Mutex::new(accept_rx),
+                datagrams: Mutex::new(datagram_rx),
+            }),
+        }
+    }
+
+    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream), Error> {
+        let core = &self.inner.core;
+        let id = core.next_id.fetch_add(2, Ordering::Relaxed);
+        let streams = core.register(id);
+        core.send(id, KIND_OPEN, Bytes::new())?;
+        Ok(streams)
+    }
+
+    pub async fn accept_bi(&self) -> Result<(SendStream, RecvStream), Error> {
+        match self.inner.accept.lock().await.recv().await {
+            Some(streams) => Ok(streams),
+            None => Err(self.inner.core.error()),
+        }
+    }
+
+    pub fn send_datagram(&self, data: Bytes) -> Result<(), Error> {
+        self.inner.core.send_datagram(data)
+    }
+
+    pub async fn read_datagram(&self) -> Result<Bytes, Error> {
+        match self.inner.datagrams.lock().await.recv().await {
+            Some(d) => Ok(d),
+            None => Err(self.inner.core.error()),
+        }
+    }
+
+    pub fn max_datagram_size(&self) -> usize {
+        MAX_PAYLOAD
+    }
+
+    pub fn close(&self, reason: &[u8]) {
+        let reason = Bytes::copy_from_slice(&reason[..reason.len().min(MAX_PAYLOAD)]);
+        let _ = self.inner.core.send(0, KIND_CLOSE, reason);
+        self.inner.core.fail(Error::Closed);
+    }
+
+    pub fn close_reason(&self) -> Option<Error> {
+        self.inner.core.error.lock().unwrap().clone()
+    }
+}
+
+pub struct SendStream {
+    id: u32,
+    credit: Arc<Semaphore>,
+    core: Arc<Core>,
+    finished: bool,
+}
+
+impl SendStream {
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.write_all_chunks(&mut [Bytes::copy_from_slice(data)])
+            .await
+    }
+
+    pub async fn write_all_chunks(&mut self, chunks: &mut [Bytes]) -> Result<(), Error> {
+        for chunk in chunks.iter_mut() {
+            while !chunk.is_empty() {
+                let d = chunk.split_to(chunk.len().min(MAX_FRAME));
+                match self
+                    .credit
+                    .acquire_many((d.len() + FRAME_COST) as u32)
+                    .await
+                {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => return Err(self.core.error()),
+                }
+                self.core.send(self.id, KIND_DATA, d)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.finished = true;
+        self.core.send(self.id, KIND_FIN, Bytes::new())
+    }
+}
+
+impl Drop for SendStream {
+    fn drop(&mut self) {
+        // like quinn, dropping a stream finishes it
+        if !self.finished {
+            let _ = self.finish();
+        }
+        let mut streams = self.core.streams.lock().unwrap();
+        if streams.get(&self.id).map(|s| s.recv_dropped) == Some(true) {
+            streams.remove(&self.id);
+        }
+    }
+}
+
+pub struct RecvStream {
+    id: u32,
+    rx: mpsc::Receiver<Bytes>,
+    core: Arc<Core>,
+    eof: bool,
+}
+
+impl RecvStream {
+    /// The next chunk, or None once the peer finished the stream.
+    pub async fn read_chunk(&mut self) -> Result<Option<Bytes>, Error> {
+        if self.eof {
+            return Ok(None);
+        }
+        match self.rx.recv().await {
+            Some(d) if d.is_empty() => {
+                self.eof = true;
+                Ok(None)
+            }
+            Some(d) => {
+                let n = d.len() + FRAME_COST;
+                if let Some(state) = self.core.streams.lock().unwrap().get_mut(&self.id) {
+                    state.window += n;
+                }
+                let credit = Bytes::copy_from_slice(&(n as u32).to_be_bytes());
+                // the peer may already be gone, the data is still good
+                let _ = self.core.send(self.id, KIND_WINDOW, credit);
+                Ok(Some(d))
+            }
+            None => Err(self.core.error()),
+        }
+    }
+}
+
+impl Drop for RecvStream {
+    fn drop(&mut self) {
+        let mut streams = self.core.streams.lock().unwrap();
+        let state = match streams.get_mut(&self.id) {
+            Some(state) => state,
+            None => return,
+        };
+        // the map holds the only other reference to the credit once the send half is gone
+        if Arc::strong_count(&state.credit) == 1 {
+            streams.remove(&self.id);
+        } else {
+            state.recv_dropped = true;
+        }
+    }
+}
+
+async fn write_loop<W: AsyncWrite + Unpin>(
+    core: Arc<Core>,
+    write: W,
+    mut out: mpsc::UnboundedReceiver<Option<Frame>>,
+    mut datagrams: mpsc::Receiver<Frame>,
+) {
+    let mut write = tokio::io::BufWriter::with_capacity(MAX_FRAME * 2, write);
+    let ret: std::io::Result<()> = async {
+        loop {
+            // datagrams are small and mostly acks and heartbeats, so they go first
+            let (header, payload) = tokio::select! {
+                biased;
+                Some(frame) = datagrams.recv() => frame,
+                frame = out.recv() => match frame {
+                    Some(Some(frame)) => frame,
+                    _ => break,
+                },
+            };
+            write.write_all(&header).await?;
+            write.write_all(&payload).await?;
+            if out.is_empty() && datagrams.is_empty() {
+                write.flush().await?;
+            }
+        }
+        write.flush().await?;
+        write.shutdown().await
+    }
+    .await;
+    if let Err(e) = ret {
+        core.fail(Error::Lost(e.to_string()));
+    }
+}
+
+async fn read_loop<R: AsyncRead + Unpin>(
+    core: Arc<Core>,
+    read: R,
+    client: bool,
+    accept: mpsc::Sender<(SendStream, RecvStream)>,
+    datagrams: mpsc::Sender<Bytes>,
+) {
+    let mut read = tokio::io::BufReader::with_capacity(MAX_FRAME * 2, read);
+    let mut failed = core.failed.subscribe();
+    let frames = async {
+        // the peer opens streams with ids of the other parity, each higher than the last
+        let mut next_peer_id: u64 = if client { 2 } else { 1 };
+        loop {
+            let mut header = [0; HEADER_SIZE];
+            if let Err(e) = read.read_exact(&mut header).await {
+                if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                    return Ok(Error::Lost("eof".to_string()));
+                }
+                return Err(e);
+            }
+            let id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+            let kind = header[4];
+            let len = u16::from_be_bytes([header[5], header[6]]) as usize;
+            let mut payload = BytesMut::zeroed(len);
+            read.read_exact(&mut payload).await?;
+            let payload = payload.freeze();
+
+            match kind {
+                KIND_OPEN => {
+                    if (id as u64) < next_peer_id || (id as u64 - next_peer_id) & 1 != 0 {
+                        return Err(violation(format!("stream {} can't be opened", id)));
+                    }
+                    next_peer_id = id as u64 + 2;
+                    if core.streams.lock().unwrap().len() >= MAX_STREAMS {
+                        return Err(violation("too many streams".to_string()));
+                    }
+                    // only registered streams wait here, so there is always room
+                    let _ = accept.try_send(core.register(id));
+                }
+                KIND_DATA if !payload.is_empty() => {
+                    let mut streams = core.streams.lock().unwrap();
+                    if let Some(state) = streams.get_mut(&id) {
+                        let cost = payload.len() + FRAME_COST;
+                        if cost > state.window {
+                            return Err(violation(format!("stream {} overran its window", id)));
+                        }
+                        state.window -= cost;
+                        if let Some(tx) = &state.data {
+                            // the window keeps the queue from filling up
+                            let _ = tx.try_send(payload);
+                        }
+                    }
+                }
+                KIND_FIN => {
+                    let mut streams = core.streams.lock().unwrap();
+                    if let Some(tx) = streams.get_mut(&id).and_then(|s| s.data.take()) {
+                        let _ = tx.try_send(Bytes::new());
+                    }
+                }
+                KIND_WINDOW if payload.len() == 4 => {
+                    let n = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                    let streams = core.streams.lock().unwrap();
+                    if let Some(state) = streams.get(&id) {
+                        state.credit.add_permits(n as usize);
+                    }
+                }
+                KIND_DATAGRAM => {
+                    // like QUIC, a datagram nobody reads in time is lost
+                    let _ = datagrams.try_send(payload);
+                }
+                KIND_CLOSE if payload.is_empty() => return Ok(Error::Closed),
+                KIND_CLOSE => {
+                    let reason = String::from_utf8_lossy(&payload).into_owned();
+                    return Ok(Error::ClosedWith(reason));
+                }
+                _ => return Err(violation(format!("invalid frame kind: {}", kind))),
+            }
+        }
+    };
+    tokio::select! {
+        ret = frames => match ret {
+            Ok(e) => core.fail(e),
+            Err(e) => core.fail(Error::Lost(e.to_string())),
+        },
+        // closed locally, or the writer broke: don't wait for the peer
+        _ = failed.wait_for(|failed| *failed) => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use tokio::io::AsyncWriteExt;
+
+    /// Plays a peer that writes frames as it likes.
+    async fn raw(io: &mut tokio::io::DuplexStream, id: u32, kind: u8, payload: &[u8]) {
+        let (header, payload) = super::frame(id, kind, Bytes::copy_from_slice(payload)).unwrap();
+        let _ = io.write_all(&header).await;
+        let _ = io.write_all(&payload).await;
+    }
+
+    async fn lost(conn: &super::Connection) -> String {
+        match conn.read_datagram().await {
+            Err(super::Error::Lost(reason)) => reason,
+            ret => panic!("connection not lost: {:?}", ret),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streams() {
+        let (a, b) = tokio::io::duplex(1024);
+        let client = super::Connection::new(a, true);
+        let server = super::Connection::new(b, false);
+
+        let (mut send, mut recv) = client.open_bi().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        let (mut peer_send, mut peer_recv) = server.accept_bi().await.unwrap();
+        assert_eq!(
+            &peer_recv.read_chunk().await.unwrap().unwrap()[..],
+            b"hello"
+        );
+
+        // larger than a frame and than the duplex buffer
+        let big = Bytes::from(vec![7; 100_000]);
+        let writer = tokio::spawn(async move {
+            peer_send.write_all_chunks(&mut [big]).await.unwrap();
+            peer_send
+        });
+        let mut got = 0;
+        while got < 100_000 {
+            got += recv.read_chunk().await.unwrap().unwrap().len();
+        }
+        assert_eq!(got, 100_000);
+        drop(writer.await.unwrap());
+        assert_eq!(recv.read_chunk().await.unwrap(), None);
+
+        server.send_datagram(Bytes::from_static(b"ping")).unwrap();
+        assert_eq!(&client.read_datagram().await.unwrap()[..], b"ping");
+
+        drop(server);
+        assert_eq!(
+            client.read_datagram().await.unwrap_err(),
+            super::Error::Closed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_close_reason() {
+        let (a, b) = tokio::io::duplex(1024);
+        let client = super::Connection::new(a, true);
+        let server = super::Connection::new(b, false);
+        server.close(b"go away");
+        assert_eq!(
+            client.read_datagram().await.unwrap_err(),
+            super::Error::ClosedWith("go away".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_window_overrun() {
+        let (a, mut b) = tokio::io::duplex(1 << 16);
+        let client = super::Connection::new(a, true);
+        tokio::spawn(async move {
+            raw(&mut b, 2, super::KIND_OPEN, b"").await;
+            let data = vec![0; super::MAX_PAYLOAD];
+            for _ in 0..=super::WINDOW / data.len() {
+                raw(&mut b, 2, super::KIND_DATA, &data).await;
+            }
+            b
+        });
+        // nothing reads the stream, so it never grants more
+        let _streams = client.accept_bi().await.unwrap();
+        assert_eq!(lost(&client).await, "stream 2 overran its window");
+    }
+
+    #[tokio::test]
+    async fn test_stream_ids() {
+        // a reused id
+        let (a, mut b) = tokio::io::duplex(1024);
+        let client = super::Connection::new(a, true);
+        raw(&mut b, 4, super::KIND_OPEN, b"").await;
+        raw(&mut b, 2, super::KIND_OPEN, b"").await;
+        assert_eq!(lost(&client).await, "stream 2 can't be opened");
+
+        // one of ours
+        let (a, mut b) = tokio::io::duplex(1024);
+        let client = super::Connection::new(a, true);
+        raw(&mut b, 3, super::KIND_OPEN, b"").await;
+        assert_eq!(lost(&client).await, "stream 3 can't be opened");
+
+        let (a, mut b) = tokio::io::duplex(1 << 16);
+        let client = super::Connection::new(a, true);
+        for i in 0..=super::MAX_STREAMS as u32 {
+            raw(&mut b, 2 + i * 2, super::KIND_OPEN, b"").await;
+        }
+        assert_eq!(lost(&client).await, "too many streams");
+    }
+
+    #[tokio::test]
+    async fn test_too_large() {
+        let (a, _b) = tokio::io::duplex(1024);
+        let client = super::Connection::new(a, true);
+        let max = client.max_datagram_size();
+        assert!(max + super::HEADER_SIZE <= u16::MAX as usize);
+        client.send_datagram(Bytes::from(vec![0; max])).unwrap();
+        assert_eq!(
+            client.send_datagram(Bytes::from(vec![0; max + 1])),
+            Err(super::Error::TooLarge(max + 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_datagram_queue() {
+        // nothing reads the other end, so the writer soon blocks
+        let (a, _b) = tokio::io::duplex(1024);
+        let client = super::Connection::new(a, true);
+        let d = Bytes::from(vec![0; 1024]);
+        let sent = (0..2 * super::DATAGRAM_QUEUE)
+            .take_while(|_| client.send_datagram(d.clone()).is_ok())
+            .count();
+        assert!(sent < 2 * super::DATAGRAM_QUEUE);
+        assert_eq!(client.send_datagram(d), Err(super::Error::Full));
+    }
+
+    #[tokio::test]
+    async fn test_lost() {
+        let (a, b) = tokio::io::duplex(1024);
+        let client = super::Connection::new(a, true);
+        let (_send, mut recv) = client.open_bi().await.unwrap();
+        drop(b);
+        assert!(matches!(
+            recv.read_chunk().await,
+            Err(super::Error::Lost(_))
+        ));
+    }
+}
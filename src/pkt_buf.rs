@@ -1,70 +1,119 @@
+use bytes::{Buf, Bytes, BytesMut};
 use std::collections::VecDeque;
 
-pub struct DataBuf {
-    pub buf: VecDeque<Vec<u8>>,
-}
+const HEADER_SIZE: usize = 6;
 
-pub fn to_pkt(id: u32, data: Vec<u8>) -> Vec<u8> {
-    let mut buf = vec![];
-    buf.extend(id.to_be_bytes().iter());
-    buf.extend((data.len() as u16).to_be_bytes().iter());
-    buf.extend(data);
-    buf
+/// Chunks received from a stream, consumed without copying whenever an item
+/// lies within a single chunk.
+#[derive(Default)]
+pub struct ChunkBuf {
+    chunks: VecDeque<Bytes>,
+    len: usize,
 }
 
-impl DataBuf {
-    pub fn new() -> Self {
-        Self {
-            buf: VecDeque::new(),
+impl ChunkBuf {
+    pub fn push(&mut self, chunk: Bytes) {
+        if chunk.is_empty() {
+            return;
         }
+        self.len += chunk.len();
+        self.chunks.push_back(chunk);
     }
 
-    pub fn push(&mut self, pkt: Vec<u8>) {
-        self.buf.push_back(pkt);
+    pub fn len(&self) -> usize {
+        self.len
     }
-}
 
-impl Iterator for DataBuf {
-    type Item = (u32, Vec<u8>);
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut id: Option<u32> = None;
-        let mut len: Option<u16> = None;
-        let mut buf = vec![];
-        loop {
-            match self.buf.pop_front() {
-                Some(d) => buf.extend(d),
-                None => break,
+    fn peek<const N: usize>(&self) -> Option<[u8; N]> {
+        if self.len < N {
+            return None;
+        }
+        let mut out = [0; N];
+        let mut filled = 0;
+        for chunk in self.chunks.iter() {
+            let n = (N - filled).min(chunk.len());
+            out[filled..filled + n].copy_from_slice(&chunk[..n]);
+            filled += n;
+            if filled == N {
+                break;
             }
+        }
+        Some(out)
+    }
 
-            if buf.len() < 6 {
-                continue;
-            }
-            if id.is_none() {
-                id = Some(u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]));
+    fn take(&mut self, n: usize) -> Bytes {
+        assert!(n <= self.len);
+        self.len -= n;
+        let front = match self.chunks.front_mut() {
+            Some(front) => front,
+            None => return Bytes::new(),
+        };
+        if front.len() >= n {
+            let out = front.split_to(n);
+            if front.is_empty() {
+                self.chunks.pop_front();
             }
-            if len.is_none() {
-                len = Some(u16::from_be_bytes([buf[4], buf[5]]));
-            }
-            if buf.len() >= len.unwrap() as usize + 6 {
-                buf = buf.split_off(6);
-                let d = buf.split_off(len.unwrap() as usize);
-                if d.len() > 0 {
-                    self.buf.push_front(d);
-                }
-                return Some((id.unwrap(), buf));
+            return out;
+        }
+        let mut out = BytesMut::with_capacity(n);
+        while out.len() < n {
+            let front = self.chunks.front_mut().unwrap();
+            let m = (n - out.len()).min(front.len());
+            out.extend_from_slice(&front[..m]);
+            front.advance(m);
+            if front.is_empty() {
+                self.chunks.pop_front();
             }
         }
+        out.freeze()
+    }
+}
+
+/// Header of a data packet. The payload follows it as a separate chunk, so it is never copied.
+pub fn to_pkt(id: u32, data: Bytes) -> [Bytes; 2] {
+    let mut header = BytesMut::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(&id.to_be_bytes());
+    header.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    [header.freeze(), data]
+}
+
+#[derive(Default)]
+pub struct DataBuf {
+    pub buf: ChunkBuf,
+}
+
+impl DataBuf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, pkt: Bytes) {
+        self.buf.push(pkt);
+    }
+}
+
+impl Iterator for DataBuf {
+    type Item = (u32, Bytes);
 
-        if buf.len() > 0 {
-            self.buf.push_front(buf);
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.buf.peek::<HEADER_SIZE>()?;
+        let id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if self.buf.len() < HEADER_SIZE + len {
+            return None;
         }
-        None
+        self.buf.take(HEADER_SIZE);
+        Some((id, self.buf.take(len)))
     }
 }
 
+#[derive(Default)]
 pub struct AckBuf {
-    pub buf: VecDeque<Vec<u8>>,
+    pub buf: ChunkBuf,
 }
 
 pub fn to_ack_pkt(id: u32) -> Vec<u8> {
@@ -73,13 +122,11 @@ pub fn to_ack_pkt(id: u32) -> Vec<u8> {
 
 impl AckBuf {
     pub fn new() -> Self {
-        Self {
-            buf: VecDeque::new(),
-        }
+        Self::default()
     }
 
-    pub fn push(&mut self, pkt: Vec<u8>) {
-        self.buf.push_back(pkt);
+    pub fn push(&mut self, pkt: Bytes) {
+        self.buf.push(pkt);
     }
 }
 
@@ -87,44 +134,65 @@ impl Iterator for AckBuf {
     type Item = u32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut buf = vec![];
-        loop {
-            match self.buf.pop_front() {
-                Some(d) => buf.extend(d),
-                None => break,
-            }
-
-            if buf.len() < 4 {
-                continue;
-            }
-            let id = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
-            return Some(id);
-        }
-        None
+        let id = self.buf.peek::<4>()?;
+        self.buf.take(4);
+        Some(u32::from_be_bytes(id))
     }
 }
 
 #[cfg(test)]
 mod test {
+    use bytes::Bytes;
+
     #[test]
     fn test_buf() {
         let mut buf = super::DataBuf::new();
 
-        buf.push(vec![0x00, 0x00, 0x00, 0x01, 0x00, 0x01]);
+        buf.push(Bytes::from_static(&[0x00, 0x00, 0x00, 0x01, 0x00, 0x01]));
         assert_eq!(buf.next(), None);
-        buf.push(vec![0x00]);
-        assert_eq!(buf.next(), Some((1, vec![0x00])));
+        buf.push(Bytes::from_static(&[0x00]));
+        assert_eq!(buf.next(), Some((1, Bytes::from_static(&[0x00]))));
         assert_eq!(buf.next(), None);
         assert_eq!(buf.buf.len(), 0);
 
-        buf.push(vec![0x00, 0x00, 0x00, 0x02, 0x00, 0x00]);
-        assert_eq!(buf.next(), Some((2, vec![])));
+        buf.push(Bytes::from_static(&[0x00, 0x00, 0x00, 0x02, 0x00, 0x00]));
+        assert_eq!(buf.next(), Some((2, Bytes::new())));
         assert_eq!(buf.next(), None);
         assert_eq!(buf.buf.len(), 0);
 
-        buf.push(vec![0x00, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00]);
-        assert_eq!(buf.next(), Some((3, vec![0x00])));
+        buf.push(Bytes::from_static(&[
+            0x00, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00,
+        ]));
+        assert_eq!(buf.next(), Some((3, Bytes::from_static(&[0x00]))));
         assert_eq!(buf.next(), None);
         assert_eq!(buf.buf.len(), 1);
     }
+
+    #[test]
+    fn test_buf_split() {
+        let mut buf = super::DataBuf::new();
+        let [header, payload] = super::to_pkt(7, Bytes::from_static(b"hello"));
+        let pkt = [header, payload].concat();
+        for b in pkt.chunks(2) {
+            buf.push(Bytes::copy_from_slice(b));
+        }
+        buf.push(pkt.clone().into());
+        assert_eq!(buf.next(), Some((7, Bytes::from_static(b"hello"))));
+        assert_eq!(buf.next(), Some((7, Bytes::from_static(b"hello"))));
+        assert_eq!(buf.next(), None);
+        assert!(buf.buf.is_empty());
+    }
+
+    #[test]
+    fn test_ack_buf() {
+        let mut buf = super::AckBuf::new();
+        buf.push(Bytes::from_static(&[0x00, 0x00, 0x00, 0x01, 0x00, 0x00]));
+        assert_eq!(buf.next(), Some(1));
+        assert_eq!(buf.next(), None);
+        buf.push(Bytes::from_static(&[0x00, 0x02, 0x00, 0x00, 0x00, 0x03]));
+        assert_eq!(buf.next(), Some(2));
+        assert_eq!(buf.next(), Some(3));
+        assert_eq!(buf.next(), None);
+        assert!(buf.buf.is_empty());
+    }
 }
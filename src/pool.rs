@@ -1,41 +1,54 @@
 use anyhow::Result;
-use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
 
 #[derive(Clone)]
 pub struct ConnPool {
     timer: tokio::time::Instant,
-    hold_timeout: u64,
+    hold_timeout: Duration,
     conns: Arc<Mutex<std::collections::HashMap<Vec<u8>, ConnInfo>>>,
-    last_active: Arc<Mutex<std::collections::HashMap<Vec<u8>, u64>>>,
+    last_active: Arc<Mutex<std::collections::HashMap<Vec<u8>, Duration>>>,
 }
 
 #[derive(Clone)]
 pub struct ConnInfo {
-    pub conn: Arc<Mutex<tokio::net::TcpStream>>,
-    pub q: Arc<Mutex<crate::queue::Queue>>,
-    pub last_ack: Arc<RwLock<u32>>,
+    pub conn: Arc<Mutex<crate::forward::Stream>>,
+    pub session: crate::utils::Session,
     pub name: Option<String>,
+    // what the client asked to reach, None for the server's --forward target
+    pub dest: Option<String>,
+    // counts the session against its backend for as long as it is pooled
+    pub lease: Option<Arc<crate::forward::Lease>>,
+    // a quicssh-rs forward, which ends with its connection
+    pub legacy: bool,
 }
 
 impl ConnInfo {
     pub fn new(
-        conn: Arc<Mutex<tokio::net::TcpStream>>,
-        q: Arc<Mutex<crate::queue::Queue>>,
-        last_ack: Arc<RwLock<u32>>,
+        conn: Arc<Mutex<crate::forward::Stream>>,
+        session: crate::utils::Session,
         name: Option<String>,
+        dest: Option<String>,
+        lease: Option<crate::forward::Lease>,
     ) -> Self {
         Self {
             conn,
-            q,
-            last_ack,
+            session,
             name,
+            dest,
+            lease: lease.map(Arc::new),
+            legacy: false,
         }
     }
+
+    pub fn legacy(mut self) -> Self {
+        self.legacy = true;
+        self
+    }
 }
 
 impl ConnPool {
-    pub fn new(hold_timeout: u64) -> Self {
+    pub fn new(hold_timeout: Duration) -> Self {
         Self {
             timer: tokio::time::Instant::now(),
             hold_timeout,
@@ -90,21 +103,49 @@ impl ConnPool {
 
     pub async fn last_active(&self, pubkey: Vec<u8>) -> Option<u64> {
         let last_active = self.last_active.lock().await;
-        let now = self.timer.elapsed().as_secs();
-        match last_active.get(&pubkey) {
-            Some(v) => Some(now - v),
+        let now = self.timer.elapsed();
+        last_active.get(&pubkey).map(|v| (now - *v).as_secs())
+    }
+
+    pub async fn qlen(&self, pubkey: Vec<u8>) -> Option<u32> {
+        let conns = self.conns.lock().await;
+        match conns.get(&pubkey) {
+            Some(v) => Some(v.session.q.lock().await.len()),
             None => None,
         }
     }
 
-    pub async fn qlen(&self, pubkey: Vec<u8>) -> Option<u32> {
+    pub async fn spilled(&self, pubkey: Vec<u8>) -> Option<u32> {
         let conns = self.conns.lock().await;
         match conns.get(&pubkey) {
-            Some(v) => Some(v.q.lock().await.len()),
+            Some(v) => Some(v.session.q.lock().await.spilled()),
             None => None,
         }
     }
 
+    pub async fn rate_limit(&self, pubkey: Vec<u8>) -> Option<u64> {
+        let conns = self.conns.lock().await;
+        conns
+            .get(&pubkey)
+            .map(|v| v.session.shaper.session().rate())
+    }
+
+    pub async fn set_rate_limit(&self, pubkey: Vec<u8>, rate: u64) -> Result<()> {
+        let conns = self.conns.lock().await;
+        match conns.get(&pubkey) {
+            Some(v) => {
+                v.session.shaper.session().set_rate(rate);
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Connection not found")),
+        }
+    }
+
+    pub async fn compression(&self, pubkey: Vec<u8>) -> Option<f64> {
+        let conns = self.conns.lock().await;
+        conns.get(&pubkey).and_then(|v| v.session.stats.ratio())
+    }
+
     pub async fn hold(&self, pubkey: Vec<u8>) -> ConnPoolHandle {
         let mut last_active = self.last_active.lock().await;
         last_active.remove(&pubkey);
@@ -114,9 +155,9 @@ impl ConnPool {
     pub async fn collect(&self) {
         log::debug!("collect start");
         let mut last_active = self.last_active.lock().await;
-        let now = self.timer.elapsed().as_secs();
+        let now = self.timer.elapsed();
         for (k, time) in last_active.clone().iter() {
-            if now - time > self.hold_timeout {
+            if now - *time > self.hold_timeout {
                 log::debug!("collect: {:?}", k);
                 last_active.remove(k);
                 self.conns.lock().await.remove(k);
@@ -125,7 +166,7 @@ impl ConnPool {
     }
 }
 
-pub fn collect_loop(pool: ConnPool, interval: std::time::Duration) {
+pub fn collect_loop(pool: ConnPool, interval: Duration) {
     let pool = pool.clone();
     tokio::spawn(async move {
         loop {
@@ -139,7 +180,7 @@ pub fn collect_loop(pool: ConnPool, interval: std::time::Duration) {
 pub struct ConnPoolHandle {
     timer: tokio::time::Instant,
     pubkey: Vec<u8>,
-    last_active: Arc<Mutex<std::collections::HashMap<Vec<u8>, u64>>>,
+    last_active: Arc<Mutex<std::collections::HashMap<Vec<u8>, Duration>>>,
 }
 
 impl Drop for ConnPoolHandle {
@@ -149,7 +190,7 @@ impl Drop for ConnPoolHandle {
         let last_active = self.last_active.clone();
         tokio::spawn(async move {
             let mut last_active = last_active.lock().await;
-            last_active.insert(pubkey, timer.elapsed().as_secs());
+            last_active.insert(pubkey, timer.elapsed());
         });
     }
 }
@@ -158,7 +199,7 @@ impl Drop for ConnPoolHandle {
 mod test {
     #[tokio::test]
     async fn test_handle() {
-        let pool = super::ConnPool::new(10);
+        let pool = super::ConnPool::new(std::time::Duration::from_secs(10));
         let pubkey = vec![1, 2, 3];
         let handle = pool.hold(pubkey.clone()).await;
         {
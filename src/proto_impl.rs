@@ -1,16 +1,24 @@
-use crate::{pool, proto, utils};
+use crate::{pool, proto, ratelimit, route, utils};
 use anyhow::Result;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 pub struct CtlServiceImpl {
     pool: Arc<Mutex<pool::ConnPool>>,
+    limits: Arc<ratelimit::Limits>,
+    routes: route::Routes,
 }
 
 impl CtlServiceImpl {
-    pub fn new(pool: pool::ConnPool) -> Self {
+    pub fn new(
+        pool: pool::ConnPool,
+        limits: Arc<ratelimit::Limits>,
+        routes: route::Routes,
+    ) -> Self {
         Self {
             pool: Arc::new(Mutex::new(pool)),
+            limits,
+            routes,
         }
     }
 }
@@ -29,9 +37,15 @@ impl proto::ctl_service_server::CtlService for CtlServiceImpl {
             let mut res_info = proto::ConnInfo::default();
             let info = pool.get(pubkey.clone()).await;
             res_info.id = utils::pubkey_to_id(&pubkey);
-            res_info.name = info.unwrap().name;
+            let info = info.unwrap();
+            res_info.name = info.name;
+            res_info.dest = info.dest;
+            res_info.legacy = info.legacy;
             res_info.last_active = pool.last_active(pubkey.clone()).await;
             res_info.pkt_buf = pool.qlen(pubkey.clone()).await;
+            res_info.spilled = pool.spilled(pubkey.clone()).await;
+            res_info.rate_limit = pool.rate_limit(pubkey.clone()).await;
+            res_info.compression = pool.compression(pubkey.clone()).await;
 
             res.conns.push(res_info);
         }
@@ -56,6 +70,48 @@ impl proto::ctl_service_server::CtlService for CtlServiceImpl {
             Err(e) => Err(tonic::Status::internal(e.to_string())),
         }
     }
+    async fn rate_limit_get(
+        &self,
+        _req: tonic::Request<proto::RateLimitGetRequest>,
+    ) -> Result<tonic::Response<proto::RateLimitGetResponse>, tonic::Status> {
+        Ok(tonic::Response::new(proto::RateLimitGetResponse {
+            global: self.limits.global().rate(),
+            session_default: self.limits.session_default(),
+        }))
+    }
+    async fn rate_limit_set(
+        &self,
+        req: tonic::Request<proto::RateLimitSetRequest>,
+    ) -> Result<tonic::Response<proto::RateLimitSetResponse>, tonic::Status> {
+        let req = req.get_ref();
+        match req.scope() {
+            proto::RateLimitScope::Global => self.limits.global().set_rate(req.rate),
+            proto::RateLimitScope::SessionDefault => self.limits.set_session_default(req.rate),
+            proto::RateLimitScope::Session => {
+                let pool = self.pool.lock().await;
+                let pubkeys = pool.list().await;
+                let pubkey = match pubkeys.iter().find(|&k| utils::pubkey_to_id(k) == req.id) {
+                    Some(pubkey) => pubkey,
+                    None => return Err(tonic::Status::not_found("Connection not found")),
+                };
+                if let Err(e) = pool.set_rate_limit(pubkey.clone(), req.rate).await {
+                    return Err(tonic::Status::internal(e.to_string()));
+                }
+            }
+        }
+        Ok(tonic::Response::new(proto::RateLimitSetResponse {}))
+    }
+    async fn route_reload(
+        &self,
+        _req: tonic::Request<proto::RouteReloadRequest>,
+    ) -> Result<tonic::Response<proto::RouteReloadResponse>, tonic::Status> {
+        match self.routes.reload().await {
+            Ok(routes) => Ok(tonic::Response::new(proto::RouteReloadResponse {
+                routes: routes as u32,
+            })),
+            Err(e) => Err(tonic::Status::failed_precondition(format!("{:#}", e))),
+        }
+    }
 }
 
 pub struct CtlClient {
@@ -86,4 +142,34 @@ impl CtlClient {
             .await
             .map(|r| r.into_inner())
     }
+
+    pub async fn rate_limit_get(&mut self) -> Result<proto::RateLimitGetResponse, tonic::Status> {
+        self.client
+            .rate_limit_get(proto::RateLimitGetRequest {})
+            .await
+            .map(|r| r.into_inner())
+    }
+
+    pub async fn rate_limit_set(
+        &mut self,
+        scope: proto::RateLimitScope,
+        conn_id: &str,
+        rate: u64,
+    ) -> Result<proto::RateLimitSetResponse, tonic::Status> {
+        self.client
+            .rate_limit_set(proto::RateLimitSetRequest {
+                scope: scope.into(),
+                id: conn_id.to_string(),
+                rate,
+            })
+            .await
+            .map(|r| r.into_inner())
+    }
+
+    pub async fn route_reload(&mut self) -> Result<proto::RouteReloadResponse, tonic::Status> {
+        self.client
+            .route_reload(proto::RouteReloadRequest {})
+            .await
+            .map(|r| r.into_inner())
+    }
 }
@@ -0,0 +1,105 @@
+use crate::utils;
+use std::net::{IpAddr, SocketAddr};
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// version 2, PROXY command
+const VERSION_PROXY: u8 = 0x21;
+// the session reaches the backend as a stream, whatever carried it here, so that is what
+// we report: TCP over IPv4 or IPv6
+const TCP4: u8 = 0x11;
+const TCP6: u8 = 0x21;
+
+/// Opaque ID of the connection, up to 128 bytes. We put the session ID here, as `ctl` shows it.
+pub const TYPE_UNIQUE_ID: u8 = 0x05;
+/// The SHA-256 of the client's public key in hex, from the range left for custom use.
+pub const TYPE_FINGERPRINT: u8 = 0xe0;
+
+fn to_v6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+/// A PROXY protocol v2 header telling the backend the session came from `src` to `dst`.
+/// An unknown `dst` is sent as the unspecified address.
+pub fn header(src: SocketAddr, dst: Option<SocketAddr>, tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+    let src_ip = utils::unmap(src.ip());
+    let (dst_ip, dst_port) = match dst {
+        Some(dst) => (utils::unmap(dst.ip()), dst.port()),
+        None if src_ip.is_ipv4() => (IpAddr::from([0; 4]), 0),
+        None => (IpAddr::from([0; 16]), 0),
+    };
+    let mut addrs = Vec::with_capacity(36);
+    let family = match (src_ip, dst_ip) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            addrs.extend_from_slice(&s.octets());
+            addrs.extend_from_slice(&d.octets());
+            TCP4
+        }
+        // mixed families are both sent as IPv6
+        (s, d) => {
+            addrs.extend_from_slice(&to_v6(s));
+            addrs.extend_from_slice(&to_v6(d));
+            TCP6
+        }
+    };
+    addrs.extend_from_slice(&src.port().to_be_bytes());
+    addrs.extend_from_slice(&dst_port.to_be_bytes());
+    for (kind, value) in tlvs {
+        addrs.push(*kind);
+        addrs.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        addrs.extend_from_slice(value);
+    }
+
+    let mut buf = Vec::with_capacity(16 + addrs.len());
+    buf.extend_from_slice(SIGNATURE);
+    buf.push(VERSION_PROXY);
+    buf.push(family);
+    buf.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&addrs);
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::{header, TYPE_UNIQUE_ID};
+
+    #[test]
+    fn test_header_v4() {
+        let hdr = header(
+            "[::ffff:192.0.2.1]:40000".parse().unwrap(),
+            Some("198.51.100.1:2222".parse().unwrap()),
+            &[(TYPE_UNIQUE_ID, b"0123abcd")],
+        );
+        assert_eq!(&hdr[..12], b"\r\n\r\n\0\r\nQUIT\n");
+        assert_eq!(hdr[12..14], [0x21, 0x11]);
+        assert_eq!(
+            u16::from_be_bytes([hdr[14], hdr[15]]) as usize,
+            hdr.len() - 16
+        );
+        assert_eq!(hdr[16..20], [192, 0, 2, 1]);
+        assert_eq!(hdr[20..24], [198, 51, 100, 1]);
+        assert_eq!(hdr[24..28], [0x9c, 0x40, 0x08, 0xae]);
+        assert_eq!(hdr[28..31], [0x05, 0, 8]);
+        assert_eq!(&hdr[31..], b"0123abcd");
+    }
+
+    #[test]
+    fn test_header_v6() {
+        let hdr = header("[2001:db8::1]:40000".parse().unwrap(), None, &[]);
+        assert_eq!(hdr[13], 0x21);
+        assert_eq!(hdr.len(), 16 + 36);
+        assert_eq!(hdr[16..18], [0x20, 0x01]);
+        // an unknown destination is all zeroes
+        assert!(hdr[32..48].iter().all(|b| *b == 0));
+        // a mixed pair is sent as IPv6
+        let hdr = header(
+            "[2001:db8::1]:40000".parse().unwrap(),
+            Some("192.0.2.1:2222".parse().unwrap()),
+            &[],
+        );
+        assert_eq!(hdr[13], 0x21);
+        assert_eq!(hdr[42..48], [0xff, 0xff, 192, 0, 2, 1]);
+    }
+}
@@ -1,20 +1,86 @@
+use crate::spill;
 use anyhow::Result;
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// a journal takes no more frames past this size, so the disk is given back a file at a
+// time as frames are acked
+const JOURNAL_SIZE: u64 = 64 << 20;
+
+#[derive(Clone)]
+pub enum Frame {
+    Mem(Bytes),
+    Disk {
+        journal: Arc<spill::Journal>,
+        offset: u64,
+        len: usize,
+    },
+}
+
+impl Frame {
+    pub async fn load(&self) -> Result<Bytes> {
+        match self {
+            Frame::Mem(buf) => Ok(buf.clone()),
+            Frame::Disk {
+                journal,
+                offset,
+                len,
+            } => {
+                let (journal, offset, len) = (journal.clone(), *offset, *len);
+                tokio::task::spawn_blocking(move || journal.read(offset, len)).await?
+            }
+        }
+    }
+}
+
+/// Frames on their way to disk, written without holding the queue.
+pub struct Batch {
+    spill: spill::Spill,
+    // None to start a new journal
+    journal: Option<Arc<spill::Journal>>,
+    frames: Vec<(u32, Bytes)>,
+}
 
 pub struct Queue {
-    q: std::collections::VecDeque<Vec<u8>>,
+    q: std::collections::VecDeque<Frame>,
     head: u32,
     max: u32,
+    // bytes held in memory, and how many frames at the front live on disk
+    mem: usize,
+    spilled: usize,
+    spill: Option<spill::Spill>,
+    journal: Option<Arc<spill::Journal>>,
+    journal_size: u64,
+    // a batch is being written
+    spilling: bool,
 }
 
 impl Queue {
     pub fn new(bit: u8) -> Self {
+        Self::with_spill(bit, None)
+    }
+
+    /// A queue that spills only holds the threshold's worth of bytes in memory, so the
+    /// number of frames is only bounded by the 32-bit IDs.
+    pub fn with_spill(bit: u8, spill: Option<spill::Spill>) -> Self {
         if bit > 32 {
             panic!("bit too large");
         }
+        let bit = match spill {
+            Some(_) => 32,
+            None => bit,
+        };
         Self {
             q: std::collections::VecDeque::new(),
             head: 1,
             max: 2u32.wrapping_pow(bit as u32).wrapping_sub(1),
+            mem: 0,
+            spilled: 0,
+            spill,
+            journal: None,
+            journal_size: JOURNAL_SIZE,
+            spilling: false,
         }
     }
     pub fn add(&self, a: u32, b: u32) -> u32 {
@@ -29,6 +95,9 @@ impl Queue {
     pub fn head(&self) -> u32 {
         self.head
     }
+    pub fn spilled(&self) -> u32 {
+        self.spilled as u32
+    }
 
     pub fn vidx(&self, idx: u32) -> u32 {
         self.add(idx, self.head)
@@ -37,16 +106,79 @@ impl Queue {
         self.sub(vidx, self.head)
     }
 
-    pub fn push(&mut self, buf: Vec<u8>) -> Result<u32> {
+    pub fn push(&mut self, buf: Bytes) -> Result<u32> {
         let vidx = self.vidx(self.len());
         log::debug!("push: {} {}", vidx, self.len());
         if self.len() > self.max {
             return Err(anyhow::anyhow!("full"));
         }
-        self.q.push_back(buf);
+        self.mem += buf.len();
+        self.q.push_back(Frame::Mem(buf));
         Ok(vidx)
     }
 
+    /// Whether memory use is over the spill threshold, for `spill` to bring it back.
+    pub fn over_threshold(&self) -> bool {
+        self.spill
+            .as_ref()
+            .is_some_and(|spill| self.mem > spill.threshold())
+    }
+
+    /// The oldest in-memory frames, enough to bring memory use back under the threshold,
+    /// unless another batch is still being written.
+    fn spill_batch(&mut self) -> Option<Batch> {
+        let spill = self.spill.as_ref()?;
+        if self.spilling {
+            return None;
+        }
+        let mut mem = self.mem;
+        let mut frames = Vec::new();
+        for (i, frame) in self.q.iter().enumerate().skip(self.spilled) {
+            if mem <= spill.threshold() {
+                break;
+            }
+            if let Frame::Mem(buf) = frame {
+                mem -= buf.len();
+                frames.push((self.vidx(i as u32), buf.clone()));
+            }
+        }
+        if frames.is_empty() {
+            return None;
+        }
+        self.spilling = true;
+        Some(Batch {
+            spill: spill.clone(),
+            journal: self
+                .journal
+                .clone()
+                .filter(|journal| journal.size() < self.journal_size),
+            frames,
+        })
+    }
+
+    /// Swaps the frames of a written batch for their place on disk, but for those acked
+    /// meanwhile. They still follow the frames spilled before, as acks only take from the front.
+    fn spilled_batch(&mut self, journal: Arc<spill::Journal>, frames: Vec<(u32, u64, usize)>) {
+        for (vidx, offset, len) in frames {
+            let idx = self.idx(vidx) as usize;
+            if idx >= self.q.len() {
+                continue;
+            }
+            if let Frame::Mem(buf) = &self.q[idx] {
+                self.mem -= buf.len();
+                self.q[idx] = Frame::Disk {
+                    journal: journal.clone(),
+                    offset,
+                    len,
+                };
+                self.spilled += 1;
+            }
+        }
+        if self.spilled > 0 {
+            self.journal = Some(journal);
+        }
+    }
+
     pub fn check(&mut self, vidx: u32) -> Result<()> {
         log::debug!("check: {}", vidx);
         let idx = self.idx(vidx);
@@ -54,14 +186,24 @@ impl Queue {
             return Err(anyhow::anyhow!("invalid idx: {}", vidx));
         }
         for _ in 0..=idx {
-            self.q.pop_front();
+            match self.q.pop_front() {
+                Some(Frame::Mem(buf)) => self.mem -= buf.len(),
+                Some(Frame::Disk { .. }) => self.spilled -= 1,
+                None => {}
+            }
+        }
+        if self.spilled == 0 {
+            // the file goes away once no replay is reading from it
+            self.journal = None;
         }
         self.head = self.add(vidx, 1);
 
         Ok(())
     }
 
-    pub fn list(&self, vidx: u32) -> Result<Vec<(u32, Vec<u8>)>> {
+    /// Packets after `vidx`. Frames are cheap to clone, and spilled ones are only read
+    /// back by `Frame::load`, so the caller can release the lock before loading and writing them out.
+    pub fn list(&self, vidx: u32) -> Result<Vec<(u32, Frame)>> {
         log::debug!("list: {}", vidx);
         let idx = self.add(self.idx(vidx), 1);
         if self.len() < idx {
@@ -77,8 +219,50 @@ impl Queue {
     }
 }
 
+/// Moves the oldest in-memory frames of `q` to its journal until memory use is back under
+/// the threshold. The writes run off the runtime threads and without holding the queue, so
+/// a slow disk only holds up this session.
+pub async fn spill(q: &Mutex<Queue>) {
+    loop {
+        let batch = match q.lock().await.spill_batch() {
+            Some(batch) => batch,
+            None => return,
+        };
+        let Batch {
+            spill,
+            journal,
+            frames,
+        } = batch;
+        let ret = tokio::task::spawn_blocking(move || -> Result<_> {
+            let journal = match journal {
+                Some(journal) => journal,
+                None => Arc::new(spill.journal()?),
+            };
+            let mut written = Vec::with_capacity(frames.len());
+            for (vidx, buf) in frames {
+                let (offset, len) = journal.append(&buf)?;
+                written.push((vidx, offset, len));
+            }
+            Ok((journal, written))
+        })
+        .await;
+        let mut q = q.lock().await;
+        q.spilling = false;
+        match ret.map_err(anyhow::Error::from).and_then(|ret| ret) {
+            Ok((journal, written)) => q.spilled_batch(journal, written),
+            Err(e) => {
+                // keeping the frames in memory is still better than failing the session
+                log::error!("spill: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     #[test]
     fn test_add_sub() {
         let q = super::Queue::new(2);
@@ -95,10 +279,10 @@ mod tests {
     fn test_queue_simple() {
         let mut q = super::Queue::new(8);
         assert_eq!(q.list(0).unwrap().len(), 0);
-        assert!(matches!(q.push(vec![3]), Ok(1)));
+        assert!(matches!(q.push(vec![3].into()), Ok(1)));
         assert!(matches!(q.check(1), Ok(())));
-        assert!(matches!(q.push(vec![3]), Ok(2)));
-        assert!(matches!(q.push(vec![3]), Ok(3)));
+        assert!(matches!(q.push(vec![3].into()), Ok(2)));
+        assert!(matches!(q.push(vec![3].into()), Ok(3)));
         assert_eq!(q.list(2).unwrap().len(), 1);
     }
     #[test]
@@ -110,7 +294,7 @@ mod tests {
         assert!(matches!(q.list(1), Err(_)));
         assert!(matches!(q.list(2), Err(_)));
         assert!(matches!(q.list(3), Err(_)));
-        assert!(matches!(q.push(vec![1]), Ok(1)));
+        assert!(matches!(q.push(vec![1].into()), Ok(1)));
         assert_eq!(q.len(), 1);
         assert_eq!(q.head(), 1);
         assert_eq!(q.list(0).unwrap().len(), 1);
@@ -126,10 +310,10 @@ mod tests {
         assert!(matches!(q.list(2), Err(_)));
         assert!(matches!(q.list(3), Err(_)));
         assert!(matches!(q.check(2), Err(_)));
-        assert!(matches!(q.push(vec![2]), Ok(2)));
-        assert!(matches!(q.push(vec![3]), Ok(3)));
-        assert!(matches!(q.push(vec![4]), Ok(0)));
-        assert!(matches!(q.push(vec![5]), Ok(1)));
+        assert!(matches!(q.push(vec![2].into()), Ok(2)));
+        assert!(matches!(q.push(vec![3].into()), Ok(3)));
+        assert!(matches!(q.push(vec![4].into()), Ok(0)));
+        assert!(matches!(q.push(vec![5].into()), Ok(1)));
         assert_eq!(q.len(), 4);
         assert_eq!(q.head(), 2);
         assert_eq!(q.list(1).unwrap().len(), 4);
@@ -140,7 +324,7 @@ mod tests {
         assert_eq!(q.list(3).unwrap()[0].0, 0);
         assert_eq!(q.list(0).unwrap().len(), 1);
         assert_eq!(q.list(0).unwrap()[0].0, 1);
-        assert!(matches!(q.push(vec![6]), Err(_)));
+        assert!(matches!(q.push(vec![6].into()), Err(_)));
         assert_eq!(q.len(), 4);
         assert_eq!(q.head(), 2);
         assert!(matches!(q.check(1), Ok(())));
@@ -158,7 +342,7 @@ mod tests {
     #[test]
     fn test_overflow() {
         let mut q = super::Queue::new(32);
-        assert!(matches!(q.push(vec![1]), Ok(1)));
+        assert!(matches!(q.push(vec![1].into()), Ok(1)));
         assert_eq!(q.len(), 1);
         assert_eq!(q.head(), 1);
         assert!(matches!(q.check(1), Ok(())));
@@ -167,6 +351,86 @@ mod tests {
         assert!(matches!(q.check(2), Err(_)));
         assert_eq!(q.len(), 0);
         assert_eq!(q.head(), 2);
-        assert!(matches!(q.push(vec![2]), Ok(2)));
+        assert!(matches!(q.push(vec![2].into()), Ok(2)));
+    }
+
+    async fn push(q: &tokio::sync::Mutex<super::Queue>, buf: Vec<u8>) -> u32 {
+        let id = q.lock().await.push(buf.into()).unwrap();
+        super::spill(q).await;
+        id
+    }
+
+    #[tokio::test]
+    async fn test_spill() {
+        let spill = crate::spill::Spill::new(std::env::temp_dir(), 4).unwrap();
+        let q = tokio::sync::Mutex::new(super::Queue::with_spill(8, Some(spill)));
+        assert_eq!(push(&q, vec![1, 1]).await, 1);
+        assert_eq!(q.lock().await.spilled(), 0);
+        assert_eq!(push(&q, vec![2, 2]).await, 2);
+        assert_eq!(q.lock().await.spilled(), 0);
+        assert_eq!(push(&q, vec![3, 3]).await, 3);
+        assert_eq!(q.lock().await.spilled(), 1);
+        assert_eq!(push(&q, vec![4, 4]).await, 4);
+        assert_eq!(q.lock().await.spilled(), 2);
+        let list = q.lock().await.list(0).unwrap();
+        assert_eq!(list.len(), 4);
+        for (id, frame) in list {
+            assert_eq!(&frame.load().await.unwrap()[..], &[id as u8, id as u8]);
+        }
+        let mut q = q.into_inner();
+        assert!(matches!(q.check(1), Ok(())));
+        assert_eq!(q.spilled(), 1);
+        assert!(q.journal.is_some());
+        assert!(matches!(q.check(2), Ok(())));
+        assert_eq!(q.spilled(), 0);
+        assert!(q.journal.is_none());
+        assert_eq!(&q.list(2).unwrap()[0].1.load().await.unwrap()[..], &[3, 3]);
+
+        // frames acked while their batch is written stay out of the queue
+        q.push(vec![5, 5, 5].into()).unwrap();
+        let batch = q.spill_batch().unwrap();
+        assert_eq!(batch.frames.len(), 2);
+        assert!(q.spill_batch().is_none());
+        q.check(3).unwrap();
+        let journal = Arc::new(batch.spill.journal().unwrap());
+        let written = batch
+            .frames
+            .iter()
+            .map(|(vidx, buf)| {
+                let (offset, len) = journal.append(buf).unwrap();
+                (*vidx, offset, len)
+            })
+            .collect();
+        q.spilled_batch(journal, written);
+        assert_eq!(q.len(), 2);
+        assert_eq!(q.spilled(), 1);
+        assert_eq!(&q.list(3).unwrap()[0].1.load().await.unwrap()[..], &[4, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_spill_past_max() {
+        let spill = crate::spill::Spill::new(std::env::temp_dir(), 4).unwrap();
+        let mut q = super::Queue::with_spill(2, Some(spill));
+        q.journal_size = 8;
+        let q = tokio::sync::Mutex::new(q);
+        // far more frames than --bufsize 2 holds
+        for i in 1..=16 {
+            assert_eq!(push(&q, vec![i; 4]).await, i as u32);
+        }
+        let mut q = q.into_inner();
+        assert_eq!(q.spilled(), 15);
+        let journal = q.journal.clone().unwrap();
+        // acked frames give their journals back
+        let first = match &q.list(0).unwrap()[0].1 {
+            super::Frame::Disk { journal, .. } => journal.clone(),
+            super::Frame::Mem(_) => panic!("not spilled"),
+        };
+        assert!(!Arc::ptr_eq(&first, &journal));
+        q.check(14).unwrap();
+        assert_eq!(Arc::strong_count(&first), 1);
+        assert_eq!(
+            &q.list(14).unwrap()[0].1.load().await.unwrap()[..],
+            &[15; 4]
+        );
     }
 }
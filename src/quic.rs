@@ -0,0 +1,211 @@
+use crate::utils;
+use anyhow::Result;
+use std::{sync::Arc, time::Duration};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Congestion {
+    NewReno,
+    Cubic,
+    Bbr,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    // Small windows and BBR to keep queues, and therefore keystroke latency, short
+    Interactive,
+    // Large windows and BBR to fill long-fat links
+    Bulk,
+}
+
+#[derive(Clone, Copy)]
+struct Preset {
+    congestion: Congestion,
+    stream_receive_window: u64,
+    receive_window: u64,
+    send_window: u64,
+    initial_rtt: Duration,
+}
+
+impl Profile {
+    fn preset(&self) -> Preset {
+        match self {
+            Profile::Interactive => Preset {
+                congestion: Congestion::Bbr,
+                stream_receive_window: 1 << 20,
+                receive_window: 2 << 20,
+                send_window: 2 << 20,
+                initial_rtt: Duration::from_millis(100),
+            },
+            Profile::Bulk => Preset {
+                congestion: Congestion::Bbr,
+                stream_receive_window: 32 << 20,
+                receive_window: 64 << 20,
+                send_window: 64 << 20,
+                initial_rtt: Duration::from_millis(100),
+            },
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct QuicOpt {
+    #[clap(long = "idle", short = 'i', default_value = "3s", value_parser = utils::parse_duration)]
+    idle: Duration,
+
+    #[clap(long = "keepalive", short = 'k', default_value = "1s", value_parser = utils::parse_duration)]
+    keepalive: Duration,
+
+    #[clap(long = "profile", value_enum)]
+    profile: Option<Profile>,
+
+    #[clap(long = "congestion", value_enum)]
+    congestion: Option<Congestion>,
+
+    #[clap(long = "stream-receive-window", value_parser = utils::parse_size)]
+    stream_receive_window: Option<u64>,
+
+    #[clap(long = "receive-window", value_parser = utils::parse_size)]
+    receive_window: Option<u64>,
+
+    #[clap(long = "send-window", value_parser = utils::parse_size)]
+    send_window: Option<u64>,
+
+    #[clap(long = "initial-rtt", value_parser = utils::parse_duration)]
+    initial_rtt: Option<Duration>,
+}
+
+impl QuicOpt {
+    /// Builds the transport config. Explicit options win over the profile,
+    /// which wins over quinn's defaults.
+    pub fn transport_config(&self) -> Result<quinn::TransportConfig> {
+        let preset = self.profile.map(|p| p.preset());
+        let mut config = quinn::TransportConfig::default();
+        if !self.idle.is_zero() {
+            config.max_idle_timeout(Some(self.idle.try_into()?));
+        }
+        if !self.keepalive.is_zero() {
+            config.keep_alive_interval(Some(self.keepalive));
+        }
+        match self.congestion.or(preset.map(|p| p.congestion)) {
+            Some(Congestion::NewReno) => {
+                config.congestion_controller_factory(Arc::new(
+                    quinn::congestion::NewRenoConfig::default(),
+                ));
+            }
+            Some(Congestion::Cubic) => {
+                config.congestion_controller_factory(Arc::new(
+                    quinn::congestion::CubicConfig::default(),
+                ));
+            }
+            Some(Congestion::Bbr) => {
+                config.congestion_controller_factory(Arc::new(
+                    quinn::congestion::BbrConfig::default(),
+                ));
+            }
+            None => {}
+        }
+        let stream_receive_window = self
+            .stream_receive_window
+            .or(preset.map(|p| p.stream_receive_window));
+        if let Some(v) = stream_receive_window {
+            config.stream_receive_window(quinn::VarInt::from_u64(v)?);
+        }
+        let receive_window = self.receive_window.or(preset.map(|p| p.receive_window));
+        if let Some(v) = receive_window {
+            config.receive_window(quinn::VarInt::from_u64(v)?);
+        }
+        let send_window = self.send_window.or(preset.map(|p| p.send_window));
+        if let Some(v) = send_window {
+            config.send_window(v);
+        }
+        let initial_rtt = self.initial_rtt.or(preset.map(|p| p.initial_rtt));
+        if let Some(v) = initial_rtt {
+            config.initial_rtt(v);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils;
+    use clap::Parser;
+    use std::sync::Arc;
+
+    #[derive(Parser)]
+    struct Opt {
+        #[clap(flatten)]
+        quic: super::QuicOpt,
+    }
+
+    fn config(args: &[&str]) -> quinn::TransportConfig {
+        let opt = Opt::parse_from(std::iter::once("test").chain(args.iter().copied()));
+        opt.quic.transport_config().unwrap()
+    }
+
+    /// The congestion controller a connection made with `config` ends up with.
+    async fn controller(config: quinn::TransportConfig) -> Box<dyn std::any::Any> {
+        let (cert, key) = utils::gen_cert().unwrap();
+        let crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![rustls::Certificate(cert)], rustls::PrivateKey(key))
+            .unwrap();
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let server = quinn::Endpoint::server(server_config, "[::1]:0".parse().unwrap()).unwrap();
+        let crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(utils::SkipServerVerification::new())
+            .with_no_client_auth();
+        let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+        client_config.transport_config(Arc::new(config));
+        let client = quinn::Endpoint::client("[::1]:0".parse().unwrap()).unwrap();
+        let connecting = client
+            .connect_with(client_config, server.local_addr().unwrap(), "localhost")
+            .unwrap();
+        let (conn, _) = tokio::join!(connecting, async { server.accept().await.unwrap().await });
+        conn.unwrap().congestion_state().into_any()
+    }
+
+    #[test]
+    fn test_profile() {
+        let bulk = format!("{:?}", config(&["--profile", "bulk"]));
+        assert!(bulk.contains(&format!("send_window: {}", 64 << 20)));
+        assert!(bulk.contains(&format!("stream_receive_window: {}", 32 << 20)));
+
+        let explicit = format!(
+            "{:?}",
+            config(&[
+                "--profile",
+                "bulk",
+                "--send-window",
+                "1M",
+                "--stream-receive-window",
+                "512K",
+                "--initial-rtt",
+                "300ms",
+            ])
+        );
+        assert!(explicit.contains(&format!("send_window: {}", 1 << 20)));
+        assert!(explicit.contains(&format!("stream_receive_window: {}", 512 << 10)));
+        assert!(explicit.contains("initial_rtt: 300ms"));
+        // what isn't given still comes from the profile
+        assert!(explicit.contains(&format!("receive_window: {}", 64 << 20)));
+    }
+
+    #[tokio::test]
+    async fn test_congestion() {
+        let bbr = controller(config(&["--profile", "interactive"])).await;
+        assert!(bbr.is::<quinn::congestion::Bbr>());
+        for (congestion, new_reno, cubic, bbr) in [
+            ("new-reno", true, false, false),
+            ("cubic", false, true, false),
+            ("bbr", false, false, true),
+        ] {
+            let c = controller(config(&["--profile", "bulk", "--congestion", congestion])).await;
+            assert_eq!(c.is::<quinn::congestion::NewReno>(), new_reno);
+            assert_eq!(c.is::<quinn::congestion::Cubic>(), cubic);
+            assert_eq!(c.is::<quinn::congestion::Bbr>(), bbr);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::Mutex, time::Instant};
+
+// the smallest burst still lets a full frame through at once
+const MIN_BURST: f64 = 32768.0;
+
+struct State {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket in bytes per second. 0 means unlimited.
+/// Waiters are served in arrival order, so a session sending a keystroke waits
+/// behind at most one frame of every other session, not behind their whole backlog.
+pub struct Bucket {
+    rate: AtomicU64,
+    state: Mutex<State>,
+}
+
+impl Bucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            state: Mutex::new(State {
+                tokens: MIN_BURST,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    pub async fn take(&self, n: usize) {
+        let rate = self.rate();
+        if rate == 0 {
+            return;
+        }
+        let rate = rate as f64;
+        let burst = (rate / 10.0).max(MIN_BURST);
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        state.tokens = (state.tokens + (now - state.last).as_secs_f64() * rate).min(burst);
+        state.last = now;
+        // go into debt instead of waiting for the whole frame to fit, and pay it off
+        // while holding the lock so later waiters queue up behind
+        state.tokens -= n as f64;
+        if state.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-state.tokens / rate)).await;
+        }
+    }
+}
+
+/// One rate, applied to each direction separately.
+pub struct Limit {
+    tx: Bucket,
+    rx: Bucket,
+}
+
+impl Limit {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            tx: Bucket::new(rate),
+            rx: Bucket::new(rate),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.tx.rate()
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.tx.set_rate(rate);
+        self.rx.set_rate(rate);
+    }
+}
+
+/// The limits a session's data passes through: its own, then the server-wide one.
+#[derive(Clone)]
+pub struct Shaper {
+    session: Arc<Limit>,
+    global: Option<Arc<Limit>>,
+}
+
+impl Shaper {
+    pub fn unlimited() -> Self {
+        Self {
+            session: Arc::new(Limit::new(0)),
+            global: None,
+        }
+    }
+
+    pub fn session(&self) -> &Limit {
+        &self.session
+    }
+
+    pub async fn tx(&self, n: usize) {
+        self.session.tx.take(n).await;
+        if let Some(global) = &self.global {
+            global.tx.take(n).await;
+        }
+    }
+
+    pub async fn rx(&self, n: usize) {
+        self.session.rx.take(n).await;
+        if let Some(global) = &self.global {
+            global.rx.take(n).await;
+        }
+    }
+}
+
+/// Server-wide limit and the limit given to new sessions, both adjustable at runtime.
+pub struct Limits {
+    global: Arc<Limit>,
+    session_default: AtomicU64,
+}
+
+impl Limits {
+    pub fn new(global: u64, session_default: u64) -> Self {
+        Self {
+            global: Arc::new(Limit::new(global)),
+            session_default: AtomicU64::new(session_default),
+        }
+    }
+
+    pub fn global(&self) -> &Limit {
+        &self.global
+    }
+
+    pub fn session_default(&self) -> u64 {
+        self.session_default.load(Ordering::Relaxed)
+    }
+
+    pub fn set_session_default(&self, rate: u64) {
+        self.session_default.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn shaper(&self) -> Shaper {
+        Shaper {
+            session: Arc::new(Limit::new(self.session_default())),
+            global: Some(self.global.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bucket() {
+        let bucket = super::Bucket::new(100_000);
+        let start = Instant::now();
+        // the burst goes through at once
+        bucket.take(32768).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        bucket.take(100_000).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        bucket.set_rate(0);
+        bucket.take(1 << 30).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,347 @@
+use crate::{quic, utils};
+use anyhow::Result;
+use clap::Parser;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+};
+
+pub const ALPN: &[u8] = b"stablessh-relay";
+
+const CMD_REGISTER: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x02;
+const STATUS_OK: u8 = 0x00;
+const STATUS_ERR: u8 = 0x01;
+
+/// Raised when the relay can't put a client through, because the server has not registered
+/// (yet) or the relay itself went away.
+#[derive(Debug)]
+pub struct Unavailable(String);
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "relay unavailable: {}", self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// A bidirectional QUIC stream as one byte stream.
+pub type Stream = tokio::io::Join<quinn::RecvStream, quinn::SendStream>;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(name = "relay")]
+pub struct Opt {
+    #[clap(flatten)]
+    quic: quic::QuicOpt,
+
+    #[clap(long = "listen", short = 'l', default_value = "[::]:2224")]
+    listen: SocketAddr,
+
+    // servers must present this to register a name
+    #[clap(long = "token")]
+    token: Option<String>,
+}
+
+struct Registration {
+    conn: quinn::Connection,
+    // what the server registered with, and must show to take the name over
+    token: String,
+}
+
+#[derive(Clone, Default)]
+struct Registry {
+    servers: Arc<Mutex<HashMap<String, Registration>>>,
+}
+
+fn token_matches(expected: &str, token: &str) -> bool {
+    ring::constant_time::verify_slices_are_equal(expected.as_bytes(), token.as_bytes()).is_ok()
+}
+
+pub async fn run(opt: Opt) -> Result<()> {
+    let endpoint = quinn::Endpoint::server(server_config(&opt)?, opt.listen)?;
+    if opt.token.is_none() {
+        log::warn!("relay: no --token, any server can register a name that is free");
+    }
+    tokio::spawn(serve(opt, endpoint));
+    utils::stop_signal_wait().await;
+    Ok(())
+}
+
+fn server_config(opt: &Opt) -> Result<quinn::ServerConfig> {
+    let (cert_der, priv_key) = utils::gen_cert()?;
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert_der)],
+            rustls::PrivateKey(priv_key),
+        )?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(opt.quic.transport_config()?));
+    Ok(server_config)
+}
+
+async fn serve(opt: Opt, endpoint: quinn::Endpoint) {
+    let registry = Registry::default();
+    while let Some(conn) = endpoint.accept().await {
+        let opt = opt.clone();
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(opt, registry, conn).await {
+                log::debug!("relay: connection error: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(opt: Opt, registry: Registry, conn: quinn::Connecting) -> Result<()> {
+    let conn = conn.await?;
+    let remote = conn.remote_address();
+    let (mut send, mut recv) = conn.accept_bi().await?;
+    match recv.read_u8().await? {
+        CMD_REGISTER => {
+            let name = read_str(&mut recv).await?;
+            let token = read_str(&mut recv).await?;
+            if opt
+                .token
+                .as_ref()
+                .is_some_and(|t| !token_matches(t, &token))
+            {
+                write_status(&mut send, Err("invalid token")).await?;
+                return Err(anyhow::anyhow!(
+                    "{} registering {:?}: invalid token",
+                    remote,
+                    name
+                ));
+            }
+            {
+                let mut servers = registry.servers.lock().await;
+                // a live name only goes to whoever registered it: the same server coming back
+                // before its old connection timed out shows the same token
+                if let Some(old) = servers.get(&name) {
+                    let live = old.conn.close_reason().is_none();
+                    if live && (old.token.is_empty() || !token_matches(&old.token, &token)) {
+                        drop(servers);
+                        write_status(&mut send, Err("name taken")).await?;
+                        return Err(anyhow::anyhow!(
+                            "{} registering {:?}: name taken",
+                            remote,
+                            name
+                        ));
+                    }
+                    old.conn.close(0_u8.into(), b"replaced");
+                }
+                let registration = Registration {
+                    conn: conn.clone(),
+                    token,
+                };
+                servers.insert(name.clone(), registration);
+            }
+            log::info!("relay: {} registered as {:?}", remote, name);
+            write_status(&mut send, Ok(())).await?;
+            let e = conn.closed().await;
+            let mut servers = registry.servers.lock().await;
+            if servers.get(&name).map(|r| r.conn.stable_id()) == Some(conn.stable_id()) {
+                servers.remove(&name);
+            }
+            log::info!("relay: {:?} unregistered: {}", name, e);
+        }
+        CMD_CONNECT => {
+            let name = read_str(&mut recv).await?;
+            let server = registry
+                .servers
+                .lock()
+                .await
+                .get(&name)
+                .map(|r| r.conn.clone());
+            let server = match server {
+                Some(server) => server,
+                None => {
+                    write_status(&mut send, Err("unknown server")).await?;
+                    return Err(anyhow::anyhow!("{} asked for unknown {:?}", remote, name));
+                }
+            };
+            let (mut server_send, server_recv) = server.open_bi().await?;
+            write_str(&mut server_send, &remote.to_string()).await?;
+            write_status(&mut send, Ok(())).await?;
+            log::debug!("relay: splicing {} to {:?}", remote, name);
+            let mut client = tokio::io::join(recv, send);
+            let mut server = tokio::io::join(server_recv, server_send);
+            tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+        }
+        cmd => return Err(anyhow::anyhow!("unknown relay command: {}", cmd)),
+    }
+    Ok(())
+}
+
+/// Client config for connections to a relay. Like the connections to servers, the relay is
+/// not verified: clients trust it to put them through to the server registered by the name.
+pub fn client_config(quic: &quic::QuicOpt) -> Result<quinn::ClientConfig> {
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(utils::SkipServerVerification::new())
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(Arc::new(quic.transport_config()?));
+    Ok(config)
+}
+
+/// Registers `name` on a relay connection. Clients are then spliced in as incoming streams.
+pub async fn register(conn: &quinn::Connection, name: &str, token: &str) -> Result<()> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_u8(CMD_REGISTER).await?;
+    write_str(&mut send, name).await?;
+    write_str(&mut send, token).await?;
+    read_status(&mut recv).await
+}
+
+/// Takes the next client spliced through by the relay, with the client's address.
+pub async fn accept(conn: &quinn::Connection) -> Result<(Stream, SocketAddr)> {
+    let (send, mut recv) = conn.accept_bi().await?;
+    let remote = read_str(&mut recv).await?.parse()?;
+    Ok((tokio::io::join(recv, send), remote))
+}
+
+/// Asks the relay for a stream spliced through to the server registered as `name`.
+pub async fn connect(conn: &quinn::Connection, name: &str) -> Result<Stream> {
+    let ret: Result<Stream> = async {
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_u8(CMD_CONNECT).await?;
+        write_str(&mut send, name).await?;
+        read_status(&mut recv).await?;
+        Ok(tokio::io::join(recv, send))
+    }
+    .await;
+    ret.map_err(|e| Unavailable(format!("{:#}", e)).into())
+}
+
+async fn read_str(recv: &mut quinn::RecvStream) -> Result<String> {
+    let len = recv.read_u8().await?;
+    let mut buf = vec![0; len as usize];
+    recv.read_exact(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}
+
+async fn write_str(send: &mut quinn::SendStream, s: &str) -> Result<()> {
+    let len = u8::try_from(s.len()).map_err(|_| anyhow::anyhow!("too long: {:?}", s))?;
+    send.write_u8(len).await?;
+    send.write_all(s.as_bytes()).await?;
+    Ok(())
+}
+
+async fn read_status(recv: &mut quinn::RecvStream) -> Result<()> {
+    match recv.read_u8().await? {
+        STATUS_OK => Ok(()),
+        _ => Err(anyhow::anyhow!("{}", read_str(recv).await?)),
+    }
+}
+
+async fn write_status(send: &mut quinn::SendStream, status: Result<(), &str>) -> Result<()> {
+    match status {
+        Ok(()) => send.write_u8(STATUS_OK).await?,
+        Err(e) => {
+            send.write_u8(STATUS_ERR).await?;
+            write_str(send, e).await?;
+            // the connection goes away next, which would take the reason with it
+            send.finish().await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accept, client_config, connect, register, serve, server_config, Opt};
+    use clap::Parser;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn relay(token: Option<&str>) -> SocketAddr {
+        let mut args = vec!["relay", "--listen", "[::1]:0"];
+        if let Some(token) = token {
+            args.extend(["--token", token]);
+        }
+        let opt = Opt::parse_from(args);
+        let endpoint = quinn::Endpoint::server(server_config(&opt).unwrap(), opt.listen).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(serve(opt, endpoint));
+        addr
+    }
+
+    async fn dial(relay: SocketAddr) -> (quinn::Endpoint, quinn::Connection) {
+        let endpoint = quinn::Endpoint::client("[::1]:0".parse().unwrap()).unwrap();
+        let config = client_config(&Opt::parse_from(["relay"]).quic).unwrap();
+        let conn = endpoint
+            .connect_with(config, relay, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        (endpoint, conn)
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let addr = relay(None).await;
+        let (_, server) = dial(addr).await;
+        register(&server, "a", "").await.unwrap();
+
+        let (_, client) = dial(addr).await;
+        let e = connect(&client, "b").await.unwrap_err();
+        assert!(e.downcast_ref::<super::Unavailable>().is_some());
+        assert!(e.to_string().ends_with("unknown server"));
+        // a connection per request
+        let (client_endpoint, client) = dial(addr).await;
+        let mut stream = connect(&client, "a").await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let (mut peer, remote) = accept(&server).await.unwrap();
+        assert_eq!(remote.port(), client_endpoint.local_addr().unwrap().port());
+        let mut buf = [0; 5];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        peer.write_all(b"world").await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        let addr = relay(None).await;
+        let (_, first) = dial(addr).await;
+        register(&first, "a", "secret").await.unwrap();
+        let (_, other) = dial(addr).await;
+        let e = register(&other, "a", "guess").await.unwrap_err();
+        assert_eq!(e.to_string(), "name taken");
+
+        // the same server, reconnecting before its old connection is gone
+        let (_, again) = dial(addr).await;
+        register(&again, "a", "secret").await.unwrap();
+        assert!(matches!(
+            first.closed().await,
+            quinn::ConnectionError::ApplicationClosed(_)
+        ));
+
+        // without a token, nobody takes a name while its server is connected
+        let (_, open) = dial(addr).await;
+        register(&open, "b", "").await.unwrap();
+        let (_, other) = dial(addr).await;
+        assert!(register(&other, "b", "").await.is_err());
+        drop(open);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let (_, other) = dial(addr).await;
+        register(&other, "b", "").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let addr = relay(Some("t0ken")).await;
+        let (_, server) = dial(addr).await;
+        let e = register(&server, "a", "t0keN").await.unwrap_err();
+        assert_eq!(e.to_string(), "invalid token");
+        let (_, server) = dial(addr).await;
+        register(&server, "a", "t0ken").await.unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+use crate::forward;
+use anyhow::Result;
+use std::{path::PathBuf, sync::Arc};
+use tokio::sync::RwLock;
+
+/// Who a route applies to.
+#[derive(Debug, Clone, PartialEq)]
+enum Match {
+    // a prefix of the hex SHA-256 of the client's public key, such as the ID ctl shows
+    Fingerprint(String),
+    // the DNS name in the client certificate, `*` matching any run of characters. The
+    // certificate is self-signed and the client puts any name it likes in it, so this sorts
+    // clients but doesn't authenticate them
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Route {
+    when: Match,
+    target: forward::Target,
+}
+
+impl Route {
+    /// Parses `fingerprint:PREFIX TARGET` or `name:PATTERN TARGET`, where the target is
+    /// anything `--forward` takes and runs to the end of the line.
+    fn parse(line: &str) -> Result<Self> {
+        let (when, target) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow::anyhow!("expected a match and a target"))?;
+        let when = match when.split_once(':') {
+            Some(("fingerprint", prefix))
+                if !prefix.is_empty() && prefix.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                Match::Fingerprint(prefix.to_ascii_lowercase())
+            }
+            Some(("name", pattern)) if !pattern.is_empty() => {
+                Match::Name(pattern.to_ascii_lowercase())
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "expected fingerprint:PREFIX or name:PATTERN: {}",
+                    when
+                ))
+            }
+        };
+        Ok(Self {
+            when,
+            target: forward::Target::parse(target.trim())?,
+        })
+    }
+
+    fn matches(&self, fingerprint: &str, name: Option<&str>) -> bool {
+        match (&self.when, name) {
+            (Match::Fingerprint(prefix), _) => fingerprint.starts_with(prefix.as_str()),
+            (Match::Name(pattern), Some(name)) => {
+                forward::glob(pattern.as_bytes(), name.to_ascii_lowercase().as_bytes())
+            }
+            (Match::Name(_), None) => false,
+        }
+    }
+}
+
+fn parse(text: &str) -> Result<Vec<Route>> {
+    let mut routes = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let route = Route::parse(line).map_err(|e| anyhow::anyhow!("line {}: {:#}", i + 1, e))?;
+        routes.push(route);
+    }
+    Ok(routes)
+}
+
+/// Targets for particular clients, read from the `--routes` file. Clients no route matches
+/// go to the `--forward` backends.
+#[derive(Debug, Clone)]
+pub struct Routes {
+    path: Option<PathBuf>,
+    routes: Arc<RwLock<Vec<Route>>>,
+}
+
+impl Routes {
+    pub async fn load(path: Option<PathBuf>) -> Result<Self> {
+        let routes = Self {
+            path,
+            routes: Default::default(),
+        };
+        if routes.path.is_some() {
+            routes.reload().await?;
+        }
+        Ok(routes)
+    }
+
+    /// Reads the file again. A file that doesn't parse leaves the routes as they were.
+    /// Sessions keep the target they started with.
+    pub async fn reload(&self) -> Result<usize> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Err(anyhow::anyhow!("no --routes file")),
+        };
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let routes = parse(&text).map_err(|e| anyhow::anyhow!("{}: {:#}", path.display(), e))?;
+        let len = routes.len();
+        *self.routes.write().await = routes;
+        log::info!("Loaded {} routes from {}", len, path.display());
+        Ok(len)
+    }
+
+    /// The target of the first route matching the client.
+    pub async fn find(&self, fingerprint: &str, name: Option<&str>) -> Option<forward::Target> {
+        self.routes
+            .read()
+            .await
+            .iter()
+            .find(|route| route.matches(fingerprint, name))
+            .map(|route| route.target.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Routes};
+    use crate::forward::Target;
+
+    const ROUTES: &str = "
+# team a has its own sshd
+name:*.team-a.example  10.0.0.1:22
+fingerprint:947DEE7A   unix:/run/sshd-admin.sock
+name:build-*           exec:/usr/sbin/sshd -i -f /etc/ssh/build.conf
+";
+
+    #[test]
+    fn test_parse() {
+        let routes = parse(ROUTES).unwrap();
+        assert_eq!(routes.len(), 3);
+        assert_eq!(
+            routes[2].target,
+            Target::Exec("/usr/sbin/sshd -i -f /etc/ssh/build.conf".to_string())
+        );
+        let e = parse("name:a 10.0.0.1:22\nid:947dee7a 10.0.0.1:22").unwrap_err();
+        assert!(e.to_string().starts_with("line 2:"));
+        assert!(parse("fingerprint:xyz 10.0.0.1:22").is_err());
+        assert!(parse("name:a").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_find() {
+        let dir = std::env::temp_dir().join(format!("stablessh-routes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("routes");
+        std::fs::write(&path, ROUTES).unwrap();
+        let routes = Routes::load(Some(path.clone())).await.unwrap();
+
+        let fingerprint = "947dee7ae836d71b11785ca787c6154f7ae30f581bfc1a2ec2c1be85ca52a868";
+        assert_eq!(
+            routes.find("00", Some("dev1.Team-A.example")).await,
+            Some(Target::Tcp("10.0.0.1:22".to_string()))
+        );
+        // the first match wins
+        assert_eq!(
+            routes.find(fingerprint, Some("build-1")).await,
+            Some(Target::Unix("/run/sshd-admin.sock".into()))
+        );
+        assert_eq!(routes.find("00", None).await, None);
+
+        // a broken file keeps the routes we have
+        std::fs::write(&path, "name:*").unwrap();
+        assert!(routes.reload().await.is_err());
+        assert!(routes.find("00", Some("build-1")).await.is_some());
+
+        std::fs::write(&path, "name:* 10.0.0.2:22").unwrap();
+        assert_eq!(routes.reload().await.unwrap(), 1);
+        assert_eq!(
+            routes.find(fingerprint, Some("build-1")).await,
+            Some(Target::Tcp("10.0.0.2:22".to_string()))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,57 +1,196 @@
-use crate::{pool, proto_impl, queue, utils};
+use crate::{
+    endpoints, forward, lb, pool, proto_impl, proxy_protocol, quic, ratelimit, relay, route, spill,
+    transport, udp, utils,
+};
 use anyhow::Result;
 use clap::Parser;
-use core::time;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
-use tokio::sync::{Mutex, RwLock};
+use ring::rand::SecureRandom;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+
+const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RELAY_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+// what quicssh-rs clients offer, carried over from the quinn examples
+const QUICSSH_ALPN: &[u8] = b"hq-29";
 
 #[derive(Parser, Debug, Clone)]
 #[clap(name = "server")]
 pub struct Opt {
-    #[clap(long = "idle", short = 'i', default_value = "3")]
-    idle: u64,
+    #[clap(flatten)]
+    quic: quic::QuicOpt,
 
-    #[clap(long = "keepalive", short = 'k', default_value = "1")]
-    keepalive: u64,
+    #[clap(flatten)]
+    conn: utils::ConnOpt,
 
     #[clap(long = "bufsize", short = 'b', default_value = "18")]
     bufsize: u8,
 
-    #[clap(long = "hold-timeout", short = 't', default_value = "604800")]
-    hold_timeout: u64,
+    #[clap(long = "hold-timeout", short = 't', default_value = "7d", value_parser = utils::parse_duration)]
+    hold_timeout: Duration,
+
+    #[clap(long = "hold-collect-interval", short = 'c', default_value = "1m", value_parser = utils::parse_duration)]
+    hold_collect_interval: Duration,
+
+    // host:ports, ports such as 2222,2230-2239, repeated for more
+    #[clap(long = "listen", short = 'l', default_value = "[::]:2222", value_parser = utils::parse_addrs)]
+    listen: Vec<utils::Addrs>,
+
+    // for clients that can't use QUIC, off unless set, e.g. the --listen addresses
+    #[clap(long = "tcp-listen", value_parser = utils::parse_addrs)]
+    tcp_listen: Vec<utils::Addrs>,
+
+    // carried in connection IDs, so `stablessh lb` keeps a connection on this server
+    #[clap(long = "server-id")]
+    server_id: Option<u16>,
+
+    // WebSocket inside TLS, for clients behind HTTP proxies, off unless set
+    #[clap(long = "ws-listen")]
+    ws_listen: Option<SocketAddr>,
+
+    // register with a relay and take clients through it, for servers behind NAT
+    #[clap(long = "relay")]
+    relay: Option<String>,
+
+    // the name clients ask the relay for, defaults to the hostname
+    #[clap(long = "relay-name")]
+    relay_name: Option<String>,
+
+    #[clap(long = "relay-token", default_value = "")]
+    relay_token: String,
 
-    #[clap(long = "hold-collect-interval", short = 'c', default_value = "60")]
-    hold_collect_interval: u64,
+    // another way to reach this server, told to clients for their reconnects,
+    // quic://, tcp:// or ws:// and host:port, repeated for more
+    #[clap(long = "advertise", value_parser = endpoints::Endpoint::parse)]
+    advertise: Vec<endpoints::Endpoint>,
 
-    #[clap(long = "listen", short = 'l', default_value = "[::]:2222")]
-    listen: SocketAddr,
+    // host:port, unix:/path/to/socket, or exec:command to run one per session, e.g. exec:sshd -i,
+    // repeated for more backends
+    #[clap(long = "forward", short = 'f', default_value = "localhost:22", value_parser = forward::Target::parse)]
+    forward: Vec<forward::Target>,
 
-    #[clap(long = "forward", short = 'f', default_value = "localhost:22")]
-    forward: String,
+    // which backend a new session goes to: first-healthy, round-robin or least-sessions
+    #[clap(
+        long = "forward-strategy",
+        value_enum,
+        default_value = "first-healthy",
+        hide_possible_values = true
+    )]
+    forward_strategy: forward::Strategy,
+
+    // none, tcp or ssh
+    #[clap(
+        long = "health-check",
+        value_enum,
+        default_value = "none",
+        hide_possible_values = true
+    )]
+    health_check: forward::HealthCheck,
+
+    #[clap(long = "health-interval", default_value = "5s", value_parser = utils::parse_duration)]
+    health_interval: Duration,
+
+    // a target for particular clients per line, `fingerprint:PREFIX TARGET` or
+    // `name:PATTERN TARGET`, re-read by `ctl route reload`
+    #[clap(long = "routes")]
+    routes_file: Option<PathBuf>,
+
+    // a destination clients may ask for with --dest, a host pattern such as *.internal or
+    // a CIDR, with optional ports, e.g. '10.0.0.0/8:22', repeated for more
+    #[clap(long = "allow-dest", value_parser = forward::Rule::parse)]
+    allow_dest: Vec<forward::Rule>,
+
+    // host:port that sessions of a client started with --udp carry their datagrams to
+    #[clap(long = "udp-forward")]
+    udp_forward: Option<String>,
+
+    // start every TCP backend connection with a PROXY protocol v2 header carrying the
+    // client's address, its key fingerprint and the session ID
+    #[clap(long = "proxy-protocol")]
+    proxy_protocol: bool,
+
+    // also take quicssh-rs clients on the QUIC listeners, as plain forwards that don't resume
+    #[clap(long = "quicssh")]
+    quicssh: bool,
 
     #[clap(long = "ctl-listen", default_value = "[::1]:50051")]
     ctl_listen: SocketAddr,
+
+    #[clap(long = "no-early-data")]
+    no_early_data: bool,
+
+    #[clap(long = "spill-threshold", value_parser = utils::parse_size)]
+    spill_threshold: Option<u64>,
+
+    #[clap(long = "spill-dir")]
+    spill_dir: Option<PathBuf>,
+
+    #[clap(long = "rate-limit", default_value = "0", value_parser = utils::parse_size)]
+    rate_limit: u64,
+
+    #[clap(long = "session-rate-limit", default_value = "0", value_parser = utils::parse_size)]
+    session_rate_limit: u64,
+}
+
+/// What the sessions of the server share, set up once in `run`.
+#[derive(Clone)]
+struct State {
+    pool: pool::ConnPool,
+    spill: Option<spill::Spill>,
+    limits: Arc<ratelimit::Limits>,
+    backends: forward::Backends,
+    routes: route::Routes,
+}
+
+impl State {
+    async fn new(opt: &Opt) -> Result<Self> {
+        let routes = route::Routes::load(opt.routes_file.clone()).await?;
+        let backends = forward::Backends::new(&opt.forward, opt.forward_strategy);
+        tokio::spawn(
+            backends
+                .clone()
+                .check_loop(opt.health_check, opt.health_interval),
+        );
+        let pool = pool::ConnPool::new(opt.hold_timeout);
+        pool::collect_loop(pool.clone(), opt.hold_collect_interval);
+        let limits = Arc::new(ratelimit::Limits::new(
+            opt.rate_limit,
+            opt.session_rate_limit,
+        ));
+        let spill = match opt.spill_threshold {
+            Some(threshold) => {
+                let dir = opt.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+                Some(spill::Spill::new(dir, threshold as usize)?)
+            }
+            None => None,
+        };
+        Ok(State {
+            pool,
+            spill,
+            limits,
+            backends,
+            routes,
+        })
+    }
 }
 
 pub async fn run(opt: Opt) -> Result<()> {
-    let conn_pool = pool::ConnPool::new(opt.hold_timeout);
-    pool::collect_loop(
-        conn_pool.clone(),
-        time::Duration::from_secs(opt.hold_collect_interval),
-    );
+    let state = State::new(&opt).await?;
     let ret = tokio::select! {
-        ret = server(opt.clone(), conn_pool.clone()) => ret,
-        ret = grpc_server(opt.clone(), conn_pool.clone()) => ret,
+        ret = server(opt.clone(), state.clone()) => ret,
+        ret = grpc_server(opt.clone(), state.clone()) => ret,
     };
 
     ret?;
     Ok(())
 }
 
-async fn grpc_server(opt: Opt, pool: pool::ConnPool) -> Result<()> {
+async fn grpc_server(opt: Opt, state: State) -> Result<()> {
     tonic::transport::Server::builder()
         .add_service(crate::proto::ctl_service_server::CtlServiceServer::new(
-            proto_impl::CtlServiceImpl::new(pool),
+            proto_impl::CtlServiceImpl::new(state.pool, state.limits, state.routes),
         ))
         .serve(opt.ctl_listen)
         .await?;
@@ -59,97 +198,667 @@ async fn grpc_server(opt: Opt, pool: pool::ConnPool) -> Result<()> {
     Ok(())
 }
 
-pub async fn server(opt: Opt, pool: pool::ConnPool) -> Result<()> {
+/// The TLS configs of the QUIC and the TCP listeners.
+fn crypto(opt: &Opt) -> Result<(rustls::ServerConfig, rustls::ServerConfig)> {
     let (cert_der, priv_key) = utils::gen_cert()?;
+    let verifier = match opt.quicssh {
+        true => utils::SkipClientVerification::optional(),
+        false => utils::SkipClientVerification::new(),
+    };
     let mut server_crypto = rustls::ServerConfig::builder()
         .with_safe_defaults()
-        .with_client_cert_verifier(utils::SkipClientVerification::new())
+        .with_client_cert_verifier(verifier)
         .with_single_cert(
             vec![rustls::Certificate(cert_der.clone())],
             rustls::PrivateKey(priv_key),
         )?;
-    server_crypto.alpn_protocols = vec![b"stablessh".to_vec()];
+    server_crypto.alpn_protocols = opt.conn.alpn_protocols();
+    // early data over TCP would need its own replay handling, and the fallback is not worth it
+    let tcp_crypto = server_crypto.clone();
+    if opt.quicssh {
+        // last, so our own clients never end up with it
+        server_crypto.alpn_protocols.push(QUICSSH_ALPN.to_vec());
+    }
+    if !opt.no_early_data {
+        server_crypto.max_early_data_size = u32::MAX;
+    }
+    Ok((server_crypto, tcp_crypto))
+}
 
+async fn server(opt: Opt, state: State) -> Result<()> {
+    let (server_crypto, tcp_crypto) = crypto(&opt)?;
     let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
-    let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
+    let mut transport_config = opt.quic.transport_config()?;
     transport_config.max_concurrent_uni_streams(0_u8.into());
-    if opt.idle > 0 {
-        transport_config.max_idle_timeout(Some(Duration::from_secs(opt.idle).try_into()?));
+    server_config.transport_config(Arc::new(transport_config));
+
+    let endpoint_config = match opt.server_id {
+        Some(id) => lb::endpoint_config(lb::ServerIdGenerator::new(id)),
+        None => quinn::EndpointConfig::default(),
+    };
+    let listen: Vec<SocketAddr> = opt.listen.iter().flat_map(|a| a.0.clone()).collect();
+    let mut endpoints = Vec::new();
+    for addr in &listen {
+        let socket = bind(*addr, socket2::Type::DGRAM, &listen)?;
+        endpoints.push(quinn::Endpoint::new(
+            endpoint_config.clone(),
+            Some(server_config.clone()),
+            socket.into(),
+            Arc::new(quinn::TokioRuntime),
+        )?);
     }
-    if opt.keepalive > 0 {
-        transport_config.keep_alive_interval(Some(Duration::from_secs(opt.keepalive)));
+    let mut listeners = Vec::new();
+    let tcp_listen: Vec<SocketAddr> = opt.tcp_listen.iter().flat_map(|a| a.0.clone()).collect();
+    for addr in &tcp_listen {
+        listeners.push((bind_tcp(*addr, &tcp_listen)?, transport::Kind::Tcp));
     }
+    if let Some(addr) = opt.ws_listen {
+        listeners.push((
+            tokio::net::TcpListener::bind(addr).await?,
+            transport::Kind::Ws,
+        ));
+    }
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tcp_crypto));
+    accept_loop(opt, endpoints.clone(), listeners, acceptor, state).await?;
 
-    let endpoint = quinn::Endpoint::server(server_config, opt.listen)?;
-    accept_loop(opt, endpoint.clone(), pool).await?;
+    for endpoint in &endpoints {
+        endpoint.close(0_u8.into(), b"");
+    }
+    for endpoint in &endpoints {
+        endpoint.wait_idle().await;
+    }
 
-    endpoint.close(0_u8.into(), b"");
-    endpoint.wait_idle().await;
+    Ok(())
+}
+
+/// Binds `addr`. An IPv6 socket is kept to IPv6 when an IPv4 one listens on the same port,
+/// since by default it would take the port for both.
+fn bind(addr: SocketAddr, ty: socket2::Type, all: &[SocketAddr]) -> Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), ty, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(all.iter().any(|a| a.is_ipv4() && a.port() == addr.port()))?;
+    }
+    if ty == socket2::Type::STREAM {
+        // as std does, so a restarted server can listen while old connections linger
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
 
+fn bind_tcp(addr: SocketAddr, all: &[SocketAddr]) -> Result<tokio::net::TcpListener> {
+    let socket = bind(addr, socket2::Type::STREAM, all)?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(tokio::net::TcpListener::from_std(socket.into())?)
+}
+
+async fn accept_loop(
+    opt: Opt,
+    endpoints: Vec<quinn::Endpoint>,
+    listeners: Vec<(tokio::net::TcpListener, transport::Kind)>,
+    acceptor: tokio_rustls::TlsAcceptor,
+    state: State,
+) -> Result<()> {
+    if let Some(relay) = opt.relay.clone() {
+        tokio::spawn(relay_loop(
+            opt.clone(),
+            endpoints[0].clone(),
+            relay,
+            acceptor.clone(),
+            state.clone(),
+        ));
+    }
+    for (listener, kind) in listeners {
+        tokio::spawn(tcp_accept_loop(
+            opt.clone(),
+            listener,
+            kind,
+            acceptor.clone(),
+            state.clone(),
+        ));
+    }
+    for endpoint in endpoints {
+        let opt = opt.clone();
+        let state = state.clone();
+        let local = endpoint.local_addr().ok();
+        tokio::spawn(async move {
+            while let Some(conn) = endpoint.accept().await {
+                let fut = handle_connection(opt.clone(), state.clone(), conn, local);
+                tokio::spawn(async move {
+                    match fut.await {
+                        Ok(_) => {}
+                        Err(e) => {
+                            log::error!("Connection error: {:?}", e);
+                        }
+                    }
+                });
+            }
+        });
+    }
+    utils::stop_signal_wait().await;
     Ok(())
 }
 
-async fn accept_loop(opt: Opt, endpoint: quinn::Endpoint, pool: pool::ConnPool) -> Result<()> {
-    tokio::spawn(async move {
-        while let Some(conn) = endpoint.accept().await {
-            let fut = handle_connection(opt.clone(), pool.clone(), conn);
-            tokio::spawn(async move {
-                match fut.await {
-                    Ok(_) => {}
-                    Err(e) => {
+async fn tcp_accept_loop(
+    opt: Opt,
+    listener: tokio::net::TcpListener,
+    kind: transport::Kind,
+    acceptor: tokio_rustls::TlsAcceptor,
+    state: State,
+) {
+    loop {
+        let (tcp, remote) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("TCP accept error: {:?}", e);
+                continue;
+            }
+        };
+        let opt = opt.clone();
+        let state = state.clone();
+        let acceptor = acceptor.clone();
+        let local = tcp.local_addr().ok();
+        tokio::spawn(async move {
+            let ret = async {
+                let handshake = async {
+                    match kind {
+                        transport::Kind::Ws => transport::accept_ws(acceptor, tcp, remote).await,
+                        _ => transport::accept_tcp(acceptor, tcp, remote).await,
+                    }
+                };
+                let conn = tokio::time::timeout(TCP_HANDSHAKE_TIMEOUT, handshake).await??;
+                handle_session(opt, state, conn, None, local).await
+            };
+            if let Err(e) = ret.await {
+                log::error!("Connection error: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Keeps the server registered with the relay, reconnecting when the relay goes away.
+/// Sessions outlive the relay connection like any other, so clients resume through the next one.
+async fn relay_loop(
+    opt: Opt,
+    endpoint: quinn::Endpoint,
+    relay: String,
+    acceptor: tokio_rustls::TlsAcceptor,
+    state: State,
+) {
+    let name = match &opt.relay_name {
+        Some(name) => name.clone(),
+        None => hostname::get()
+            .ok()
+            .and_then(|h| h.into_string().ok())
+            .unwrap_or_else(|| "localhost".to_string()),
+    };
+    loop {
+        let ret: Result<()> = async {
+            let addr = utils::resolve(&relay, false, false)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("could not resolve {}", relay))?;
+            let config = relay::client_config(&opt.quic)?;
+            let conn = endpoint
+                .connect_with(config, addr, utils::server_name(&relay))?
+                .await?;
+            relay::register(&conn, &name, &opt.relay_token).await?;
+            log::info!("Registered with relay {} as {:?}", relay, name);
+            loop {
+                let (stream, remote) = relay::accept(&conn).await?;
+                let opt = opt.clone();
+                let state = state.clone();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let ret = async {
+                        let handshake = transport::accept_relayed(acceptor, stream, remote);
+                        let conn = tokio::time::timeout(TCP_HANDSHAKE_TIMEOUT, handshake).await??;
+                        // the address the client reached is the relay's
+                        handle_session(opt, state, conn, None, None).await
+                    };
+                    if let Err(e) = ret.await {
                         log::error!("Connection error: {:?}", e);
                     }
-                }
-            });
+                });
+            }
         }
-    });
-    utils::stop_signal_wait().await;
-    Ok(())
+        .await;
+        if let Err(e) = ret {
+            log::error!("Relay {} error: {:?}", relay, e);
+        }
+        tokio::time::sleep(RELAY_RETRY_INTERVAL).await;
+    }
 }
 
 async fn handle_connection(
     opt: Opt,
-    mut conn_pool: pool::ConnPool,
+    state: State,
     conn: quinn::Connecting,
+    local: Option<SocketAddr>,
 ) -> Result<()> {
-    let conn = conn.await?;
-    let (pubkey, name) = utils::x509(
-        &conn
-            .peer_identity()
-            .unwrap()
-            .downcast::<Vec<rustls::Certificate>>()
-            .unwrap()
-            .first()
-            .unwrap(),
-    )?;
-    let conn_info = match conn_pool.get(pubkey.clone()).await {
+    let (conn, mut accepted) = match conn.into_0rtt() {
+        Ok((conn, accepted)) => (conn, Some(accepted)),
+        // without 0.5-RTT, wait for the handshake like any other connection
+        Err(connecting) => (connecting.await?, None),
+    };
+    // Only a resumed TLS session tells us who the client is before the handshake completes.
+    if conn.peer_identity().is_none() {
+        if let Some(accepted) = accepted.take() {
+            established(&conn, accepted).await?;
+        }
+    }
+    // the endpoint may listen on any address, the packets tell which one the client reached
+    let local =
+        local.map(|local| SocketAddr::new(conn.local_ip().unwrap_or(local.ip()), local.port()));
+    if legacy(&conn) {
+        return handle_legacy(opt, state, conn, local).await;
+    }
+    if multiplexed(&conn)? {
+        if let Some(accepted) = accepted.take() {
+            established(&conn, accepted).await?;
+        }
+        return handle_multiplexed(opt, state, conn, local).await;
+    }
+    handle_session(
+        opt,
+        state,
+        transport::Connection::Quic(conn),
+        accepted,
+        local,
+    )
+    .await
+}
+
+fn multiplexed(conn: &quinn::Connection) -> Result<bool> {
+    match transport::Connection::Quic(conn.clone())
+        .peer_certificates()
+        .and_then(|certs| certs.into_iter().next())
+    {
+        Some(cert) => utils::x509_multiplexed(&cert),
+        None => Ok(false),
+    }
+}
+
+/// Serves the connection of a client daemon, which runs each of its sessions over a stream
+/// of its own, starting with the session's ID and destination.
+async fn handle_multiplexed(
+    opt: Opt,
+    state: State,
+    conn: quinn::Connection,
+    local: Option<SocketAddr>,
+) -> Result<()> {
+    loop {
+        let (send, mut recv) = conn.accept_bi().await?;
+        let opt = opt.clone();
+        let state = state.clone();
+        let conn = conn.clone();
+        tokio::spawn(async move {
+            let ret = async {
+                let (id, dest) = transport::read_session_header(&mut recv).await?;
+                let session = transport::multiplexed(&conn, send, recv, id, dest, false);
+                handle_session(opt, state, session, None, local).await
+            };
+            if let Err(e) = ret.await {
+                log::error!("Session error: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Whether the client is quicssh-rs, whose protocol is only offered with `--quicssh`.
+fn legacy(conn: &quinn::Connection) -> bool {
+    transport::Connection::Quic(conn.clone()).alpn().as_deref() == Some(QUICSSH_ALPN)
+}
+
+/// Serves a quicssh-rs client, each stream it opens going to a backend as it is. Nothing is
+/// queued, so a session ends with its connection.
+async fn handle_legacy(
+    opt: Opt,
+    state: State,
+    conn: quinn::Connection,
+    local: Option<SocketAddr>,
+) -> Result<()> {
+    log::debug!("quicssh-rs client from {}", conn.remote_address());
+    loop {
+        let (send, recv) = match conn.accept_bi().await {
+            Ok(v) => v,
+            // how quicssh-rs says goodbye
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let opt = opt.clone();
+        let state = state.clone();
+        let remote = conn.remote_address();
+        tokio::spawn(async move {
+            let ret = legacy_session(opt, state, send, recv, remote, local);
+            if let Err(e) = ret.await {
+                log::error!("Session error: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn legacy_session(
+    opt: Opt,
+    state: State,
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    remote: SocketAddr,
+    local: Option<SocketAddr>,
+) -> Result<()> {
+    // there is no key to know the client by, so ctl gets a random one
+    let mut key = [0; transport::SESSION_ID_LEN];
+    ring::rand::SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| anyhow::anyhow!("failed to generate a session ID"))?;
+    let key = key.to_vec();
+    let (mut ssh_conn, lease) = state.backends.connect().await?;
+    if opt.proxy_protocol && ssh_conn.takes_proxy_header() {
+        let id = utils::pubkey_to_id(&key);
+        let header = proxy_protocol::header(
+            remote,
+            local,
+            &[(proxy_protocol::TYPE_UNIQUE_ID, id.as_bytes())],
+        );
+        ssh_conn.split().1.write_all(&header).await?;
+    }
+    log::info!(
+        "New legacy session from {} to {}",
+        remote,
+        state.backends.target(&lease)
+    );
+    let ssh_conn = Arc::new(Mutex::new(ssh_conn));
+    let session = utils::Session::new(0, None, state.limits.shaper());
+    let shaper = session.shaper.clone();
+    state
+        .pool
+        .insert(
+            key.clone(),
+            pool::ConnInfo::new(ssh_conn.clone(), session, None, None, Some(lease)).legacy(),
+        )
+        .await;
+    let _handle = state.pool.hold(key.clone()).await;
+
+    let mut ssh_conn = ssh_conn.lock().await;
+    let (ssh_recv, ssh_send) = ssh_conn.split();
+    let ret = tokio::try_join!(
+        legacy_copy(recv, ssh_send, shaper.clone(), false),
+        legacy_copy(ssh_recv, send, shaper, true),
+    );
+    state.pool.remove(key).await;
+    ret.map(|_| ())
+}
+
+/// Copies one way of a legacy session through its rate limits, passing the end of the
+/// stream on.
+async fn legacy_copy(
+    mut from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    shaper: ratelimit::Shaper,
+    to_client: bool,
+) -> Result<()> {
+    let mut buf = vec![0; 65536];
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        match to_client {
+            true => shaper.tx(n).await,
+            false => shaper.rx(n).await,
+        }
+        to.write_all(&buf[..n]).await?;
+        to.flush().await?;
+    }
+    to.shutdown().await?;
+    Ok(())
+}
+
+/// Attaches a connection to its session, creating the session if it is new.
+/// `accepted` is set while a QUIC connection may still be carrying 0-RTT data, `local` is
+/// the address the client reached, if known.
+async fn handle_session(
+    opt: Opt,
+    mut state: State,
+    conn: transport::Connection,
+    mut accepted: Option<quinn::ZeroRttAccepted>,
+    local: Option<SocketAddr>,
+) -> Result<()> {
+    let certs = conn
+        .peer_certificates()
+        .ok_or_else(|| anyhow::anyhow!("no client certificate"))?;
+    let cert = certs
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("no client certificate"))?;
+    let (mut pubkey, name) = utils::x509(cert)?;
+    let fingerprint = sha256::digest(pubkey.clone());
+    // the sessions of a client daemon share its key, their IDs tell them apart
+    if let Some(id) = conn.session() {
+        pubkey.extend_from_slice(id);
+    }
+    let conn_info = match state.pool.get(pubkey.clone()).await {
         Some(v) => {
             log::debug!("Reusing connection for {:?}", pubkey);
             v
         }
         None => {
-            log::debug!("Creating new connection for {:?}", pubkey);
-            let ssh_conn = Arc::new(Mutex::new(
-                tokio::net::TcpStream::connect(opt.forward).await?,
-            ));
-            let q = Arc::new(Mutex::new(queue::Queue::new(opt.bufsize)));
-            let last_ack = Arc::new(RwLock::new(0_u32));
-
-            conn_pool
+            // Opening a new session is not idempotent, so it must not be driven by early data.
+            if let (transport::Connection::Quic(quic), Some(accepted)) = (&conn, accepted.take()) {
+                established(quic, accepted).await?;
+            }
+            log::debug!(
+                "Creating new connection for {:?} over {}",
+                pubkey,
+                conn.kind()
+            );
+            let dest = match conn.dest() {
+                Some(dest) => Some(dest.to_string()),
+                None => utils::x509_dest(cert)?,
+            };
+            let udp = utils::x509_udp(cert)?;
+            let route = state.routes.find(&fingerprint, name.as_deref()).await;
+            let mut tunnel = None;
+            let ret = match (&dest, route) {
+                _ if udp => udp_target(&opt).await.map(|target| {
+                    tunnel = Some(udp::Tunnel::forward(target));
+                    (forward::Stream::Idle, None, format!("udp:{}", target))
+                }),
+                (Some(dest), _) => forward::connect_dest(&opt.allow_dest, dest)
+                    .await
+                    .map(|stream| (stream, None, dest.clone())),
+                (None, Some(target)) => target
+                    .connect()
+                    .await
+                    .map(|stream| (stream, None, target.to_string())),
+                (None, None) => state.backends.connect().await.map(|(stream, lease)| {
+                    let target = state.backends.target(&lease).to_string();
+                    (stream, Some(lease), target)
+                }),
+            };
+            let (mut ssh_conn, lease, target) = match ret {
+                Ok(v) => v,
+                Err(e) => {
+                    // the client gives up with this instead of reconnecting,
+                    // the details stay in our log
+                    let reason: &[u8] = if e.downcast_ref::<forward::Denied>().is_some() {
+                        b"destination not allowed"
+                    } else if udp && opt.udp_forward.is_none() {
+                        b"UDP forwarding not enabled"
+                    } else if dest.is_some() || udp {
+                        b"destination unreachable"
+                    } else {
+                        b"no backend available"
+                    };
+                    conn.close(reason);
+                    return Err(e);
+                }
+            };
+            if opt.proxy_protocol && ssh_conn.takes_proxy_header() {
+                // only the address the session started from, the backend connection outlives it
+                let id = utils::pubkey_to_id(&pubkey);
+                let header = proxy_protocol::header(
+                    conn.remote_address(),
+                    local,
+                    &[
+                        (proxy_protocol::TYPE_UNIQUE_ID, id.as_bytes()),
+                        (proxy_protocol::TYPE_FINGERPRINT, fingerprint.as_bytes()),
+                    ],
+                );
+                ssh_conn.split().1.write_all(&header).await?;
+            }
+            log::info!("New session for {:?} to {}", name, target);
+            let ssh_conn = Arc::new(Mutex::new(ssh_conn));
+            let mut session =
+                utils::Session::new(opt.bufsize, state.spill.clone(), state.limits.shaper());
+            let dest = match tunnel {
+                Some(tunnel) => {
+                    session = session.with_udp(tunnel);
+                    Some(target)
+                }
+                None => dest,
+            };
+
+            state
+                .pool
                 .insert(
                     pubkey.clone(),
-                    pool::ConnInfo::new(ssh_conn, q, last_ack, name),
+                    pool::ConnInfo::new(ssh_conn, session, name, dest, lease),
                 )
                 .await
                 .unwrap()
         }
     };
 
+    // A replayed ClientHello never completes the handshake, so it must not hold the session
+    // and keep the real client's reconnect waiting until it times out.
+    if let (transport::Connection::Quic(quic), Some(accepted)) = (&conn, accepted.take()) {
+        established(quic, accepted).await?;
+    }
     let mut ssh_conn = conn_info.conn.lock().await;
     let (ssh_recv, ssh_send) = ssh_conn.split();
-    let _handle = conn_pool.hold(pubkey.clone()).await;
-    utils::handle_connection(conn, conn_info.q, conn_info.last_ack, ssh_recv, ssh_send).await?;
-    conn_pool.remove(pubkey.clone()).await;
+    let _handle = state.pool.hold(pubkey.clone()).await;
+    let config = match conn.session() {
+        Some(_) => opt.conn.config().without_heartbeat(),
+        None => opt.conn.config(),
+    };
+    utils::handle_connection(
+        conn,
+        None,
+        config,
+        conn_info.session,
+        endpoints::Endpoints::Advertise(Arc::new(opt.advertise.clone())),
+        ssh_recv,
+        ssh_send,
+    )
+    .await?;
+    state.pool.remove(pubkey.clone()).await;
 
     Ok(())
 }
+
+/// Where UDP sessions go. It is resolved for each session, whose flows then all reach the
+/// same address.
+async fn udp_target(opt: &Opt) -> Result<SocketAddr> {
+    let target = opt
+        .udp_forward
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("a client asked for UDP, but there is no --udp-forward"))?;
+    tokio::net::lookup_host(target)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{}: no address", target))
+}
+
+async fn established(conn: &quinn::Connection, accepted: quinn::ZeroRttAccepted) -> Result<()> {
+    accepted.await;
+    match conn.close_reason() {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A `--quicssh` server forwarding to an echo backend, and the address of its QUIC
+    /// listener.
+    async fn quicssh_server() -> (State, SocketAddr) {
+        let backend = tokio::net::TcpListener::bind("[::1]:0").await.unwrap();
+        let forward = backend.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut recv, mut send) = stream.split();
+                    tokio::io::copy(&mut recv, &mut send).await.unwrap();
+                });
+            }
+        });
+        let opt = Opt::parse_from(["server", "--quicssh", "-f", &forward]);
+        let state = State::new(&opt).await.unwrap();
+        let (crypto, _) = crypto(&opt).unwrap();
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint = quinn::Endpoint::server(config, "[::1]:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let server = state.clone();
+        tokio::spawn(async move {
+            while let Some(conn) = endpoint.accept().await {
+                tokio::spawn(handle_connection(opt.clone(), server.clone(), conn, None));
+            }
+        });
+        (state, addr)
+    }
+
+    async fn connect(addr: SocketAddr, alpn_protocols: Vec<Vec<u8>>) -> quinn::Connection {
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(utils::SkipServerVerification::new())
+            .with_no_client_auth();
+        crypto.alpn_protocols = alpn_protocols;
+        let config = quinn::ClientConfig::new(Arc::new(crypto));
+        let endpoint = quinn::Endpoint::client("[::1]:0".parse().unwrap()).unwrap();
+        endpoint
+            .connect_with(config, addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_legacy() {
+        let (mut state, addr) = quicssh_server().await;
+        // quicssh-rs has no client certificate
+        let conn = connect(addr, vec![QUICSSH_ALPN.to_vec()]).await;
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+        let mut buf = [0; 14];
+        recv.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"SSH-2.0-test\r\n");
+
+        let keys = state.pool.list().await;
+        assert_eq!(keys.len(), 1);
+        let info = state.pool.get(keys[0].clone()).await.unwrap();
+        assert!(info.legacy);
+    }
+
+    #[tokio::test]
+    async fn test_no_legacy_alpn_for_clients() {
+        let opt = Opt::parse_from(["server", "--quicssh"]);
+        let (quic, tcp) = crypto(&opt).unwrap();
+        assert_eq!(quic.alpn_protocols.last().unwrap(), QUICSSH_ALPN);
+        assert!(!tcp.alpn_protocols.contains(&QUICSSH_ALPN.to_vec()));
+
+        let (_, addr) = quicssh_server().await;
+        // what our clients offer, from the same options as the server's
+        for args in [&["server"][..], &["server", "--no-compression"]] {
+            let alpn_protocols = Opt::parse_from(args).conn.alpn_protocols();
+            assert!(!alpn_protocols.contains(&QUICSSH_ALPN.to_vec()));
+            let conn = connect(addr, alpn_protocols).await;
+            let alpn = transport::Connection::Quic(conn).alpn();
+            assert!(alpn.is_some());
+            assert_ne!(alpn.as_deref(), Some(QUICSSH_ALPN));
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::Result;
+use bytes::Bytes;
+use ring::aead;
+use std::{
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Where and when queued frames move to disk. The key lives only in this process,
+/// so journals left behind by a crash can't be read by anyone.
+#[derive(Clone)]
+pub struct Spill {
+    dir: PathBuf,
+    threshold: usize,
+    key: Arc<aead::LessSafeKey>,
+    next_id: Arc<AtomicU32>,
+}
+
+impl Spill {
+    pub fn new(dir: PathBuf, threshold: usize) -> Result<Self> {
+        let mut key = [0; 32];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut key)
+            .map_err(|_| anyhow::anyhow!("failed to generate spill key"))?;
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &key)
+            .map_err(|_| anyhow::anyhow!("invalid spill key"))?;
+        Ok(Self {
+            dir,
+            threshold,
+            key: Arc::new(aead::LessSafeKey::new(key)),
+            next_id: Arc::new(AtomicU32::new(0)),
+        })
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn journal(&self) -> Result<Journal> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .dir
+            .join(format!("stablessh-{}-{}.journal", std::process::id(), id));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        log::debug!("spill: created {:?}", path);
+        Ok(Journal {
+            id,
+            path,
+            file,
+            len: AtomicU64::new(0),
+            key: self.key.clone(),
+        })
+    }
+}
+
+/// Append-only file of encrypted frames. The file is removed when the last frame
+/// referring to it is dropped.
+pub struct Journal {
+    id: u32,
+    path: PathBuf,
+    file: std::fs::File,
+    len: AtomicU64,
+    key: Arc<aead::LessSafeKey>,
+}
+
+impl Journal {
+    // the offset is never reused within a journal, and the id never within a process
+    fn nonce(&self, offset: u64) -> aead::Nonce {
+        let mut nonce = [0; aead::NONCE_LEN];
+        nonce[..4].copy_from_slice(&self.id.to_be_bytes());
+        nonce[4..].copy_from_slice(&offset.to_be_bytes());
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Appends a frame and returns its offset and length on disk. These are blocking calls,
+    /// and only one batch of the owning queue appends at a time.
+    pub fn append(&self, data: &[u8]) -> Result<(u64, usize)> {
+        let offset = self.len.load(Ordering::Relaxed);
+        let mut buf = data.to_vec();
+        self.key
+            .seal_in_place_append_tag(self.nonce(offset), aead::Aad::empty(), &mut buf)
+            .map_err(|_| anyhow::anyhow!("failed to seal frame"))?;
+        self.file.write_all_at(&buf, offset)?;
+        self.len.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok((offset, buf.len()))
+    }
+
+    pub fn read(&self, offset: u64, len: usize) -> Result<Bytes> {
+        let mut buf = vec![0; len];
+        self.file.read_exact_at(&mut buf, offset)?;
+        let n = self
+            .key
+            .open_in_place(self.nonce(offset), aead::Aad::empty(), &mut buf)
+            .map_err(|_| anyhow::anyhow!("corrupted journal: {:?}", self.path))?
+            .len();
+        buf.truncate(n);
+        Ok(buf.into())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        log::debug!("spill: removing {:?}", self.path);
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::error!("spill: failed to remove {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn test_journal() {
+        let spill = super::Spill::new(std::env::temp_dir(), 0).unwrap();
+        let journal = spill.journal().unwrap();
+        let path = journal.path.clone();
+        let (o1, l1) = journal.append(b"hello").unwrap();
+        let (o2, l2) = journal.append(b"world").unwrap();
+        assert_eq!(&journal.read(o2, l2).unwrap()[..], b"world");
+        assert_eq!(&journal.read(o1, l1).unwrap()[..], b"hello");
+
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(5).any(|w| w == b"hello"));
+        journal.file.write_all_at(&[raw[0] ^ 1], 0).unwrap();
+        assert!(journal.read(o1, l1).is_err());
+
+        drop(journal);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,432 @@
+use crate::{http_proxy, mux, relay, ws};
+use anyhow::Result;
+use bytes::Bytes;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    // QUIC first, TLS over TCP when QUIC can't connect
+    Auto,
+    Quic,
+    Tcp,
+    // TLS over TCP carrying a WebSocket, for networks that only let HTTP proxies out
+    Ws,
+}
+
+/// What the TLS handshake of a stream transport told us. QUIC keeps this in the connection itself.
+pub struct Info {
+    kind: &'static str,
+    remote: SocketAddr,
+    alpn: Option<Vec<u8>>,
+    certs: Option<Vec<rustls::Certificate>>,
+    // a byte stream has no RTT estimator, the handshake time stands in for it
+    rtt: Duration,
+    // set when the connection underneath carries the sessions of a client daemon
+    session: Option<Vec<u8>>,
+    // the destination such a session asked for, if any
+    dest: Option<String>,
+}
+
+/// Length of the ID a stream of a client daemon's connection starts with.
+pub const SESSION_ID_LEN: usize = 16;
+
+/// Starts a stream of a client daemon's connection: the session's ID, then the destination
+/// it asks for after its length in one octet, which is 0 for none.
+pub async fn write_session_header<W: tokio::io::AsyncWrite + Unpin>(
+    send: &mut W,
+    id: &[u8],
+    dest: Option<&str>,
+) -> Result<()> {
+    let dest = dest.unwrap_or_default();
+    let len =
+        u8::try_from(dest.len()).map_err(|_| anyhow::anyhow!("destination too long: {}", dest))?;
+    let mut header = id.to_vec();
+    header.push(len);
+    header.extend_from_slice(dest.as_bytes());
+    send.write_all(&header).await?;
+    Ok(())
+}
+
+/// Reads what `write_session_header` wrote.
+pub async fn read_session_header<R: tokio::io::AsyncRead + Unpin>(
+    recv: &mut R,
+) -> Result<(Vec<u8>, Option<String>)> {
+    let mut id = vec![0; SESSION_ID_LEN];
+    recv.read_exact(&mut id).await?;
+    let mut dest = vec![0; recv.read_u8().await? as usize];
+    recv.read_exact(&mut dest).await?;
+    let dest = match dest.is_empty() {
+        true => None,
+        false => Some(String::from_utf8(dest)?),
+    };
+    Ok((id, dest))
+}
+
+/// A session connection, either native QUIC or the same protocol multiplexed over TLS on TCP.
+#[derive(Clone)]
+pub enum Connection {
+    Quic(quinn::Connection),
+    Mux(mux::Connection, Arc<Info>),
+}
+
+impl Connection {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Connection::Quic(_) => "quic",
+            Connection::Mux(_, info) => info.kind,
+        }
+    }
+
+    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream)> {
+        match self {
+            Connection::Quic(conn) => {
+                let (send, recv) = conn.open_bi().await?;
+                Ok((SendStream::Quic(send), RecvStream::Quic(recv)))
+            }
+            Connection::Mux(conn, _) => {
+                let (send, recv) = conn.open_bi().await?;
+                Ok((SendStream::Mux(send), RecvStream::mux(recv)))
+            }
+        }
+    }
+
+    pub async fn accept_bi(&self) -> Result<(SendStream, RecvStream)> {
+        match self {
+            Connection::Quic(conn) => {
+                let (send, recv) = conn.accept_bi().await?;
+                Ok((SendStream::Quic(send), RecvStream::Quic(recv)))
+            }
+            Connection::Mux(conn, _) => {
+                let (send, recv) = conn.accept_bi().await?;
+                Ok((SendStream::Mux(send), RecvStream::mux(recv)))
+            }
+        }
+    }
+
+    pub fn send_datagram(&self, data: Bytes) -> Result<()> {
+        match self {
+            Connection::Quic(conn) => conn.send_datagram(data)?,
+            Connection::Mux(conn, _) => match conn.send_datagram(data) {
+                // as QUIC does when its buffer is full, the datagram is lost, not the connection
+                Err(mux::Error::Full) => log::debug!("datagram dropped: queue full"),
+                ret => ret?,
+            },
+        }
+        Ok(())
+    }
+
+    pub async fn read_datagram(&self) -> Result<Bytes> {
+        match self {
+            Connection::Quic(conn) => Ok(conn.read_datagram().await?),
+            Connection::Mux(conn, _) => Ok(conn.read_datagram().await?),
+        }
+    }
+
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        match self {
+            Connection::Quic(conn) => conn.max_datagram_size(),
+            Connection::Mux(conn, _) => Some(conn.max_datagram_size()),
+        }
+    }
+
+    pub fn rtt(&self) -> Duration {
+        match self {
+            Connection::Quic(conn) => conn.rtt(),
+            Connection::Mux(_, info) => info.rtt,
+        }
+    }
+
+    pub fn close(&self, reason: &[u8]) {
+        match self {
+            Connection::Quic(conn) => conn.close(0_u8.into(), reason),
+            Connection::Mux(conn, _) => conn.close(reason),
+        }
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        match self {
+            Connection::Quic(conn) => conn.remote_address(),
+            Connection::Mux(_, info) => info.remote,
+        }
+    }
+
+    pub fn peer_certificates(&self) -> Option<Vec<rustls::Certificate>> {
+        match self {
+            Connection::Quic(conn) => conn
+                .peer_identity()
+                .and_then(|id| id.downcast::<Vec<rustls::Certificate>>().ok())
+                .map(|certs| *certs),
+            Connection::Mux(_, info) => info.certs.clone(),
+        }
+    }
+
+    pub fn alpn(&self) -> Option<Vec<u8>> {
+        match self {
+            Connection::Quic(conn) => conn
+                .handshake_data()
+                .and_then(|d| d.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+                .and_then(|d| d.protocol),
+            Connection::Mux(_, info) => info.alpn.clone(),
+        }
+    }
+
+    /// The ID of the session, if it shares its QUIC connection with others.
+    pub fn session(&self) -> Option<&[u8]> {
+        match self {
+            Connection::Quic(_) => None,
+            Connection::Mux(_, info) => info.session.as_deref(),
+        }
+    }
+
+    /// The destination a session sharing its connection asked for, if any.
+    pub fn dest(&self) -> Option<&str> {
+        match self {
+            Connection::Quic(_) => None,
+            Connection::Mux(_, info) => info.dest.as_deref(),
+        }
+    }
+}
+
+pub enum SendStream {
+    Quic(quinn::SendStream),
+    Mux(mux::SendStream),
+}
+
+impl SendStream {
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            SendStream::Quic(send) => send.write_all(data).await?,
+            SendStream::Mux(send) => send.write_all(data).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn write_all_chunks(&mut self, chunks: &mut [Bytes]) -> Result<()> {
+        match self {
+            SendStream::Quic(send) => send.write_all_chunks(chunks).await?,
+            SendStream::Mux(send) => send.write_all_chunks(chunks).await?,
+        }
+        Ok(())
+    }
+
+    /// Ends the stream. Over QUIC this waits until the peer has received all of it.
+    pub async fn finish(&mut self) -> Result<()> {
+        match self {
+            SendStream::Quic(send) => send.finish().await?,
+            SendStream::Mux(send) => send.finish()?,
+        }
+        Ok(())
+    }
+}
+
+pub enum RecvStream {
+    Quic(quinn::RecvStream),
+    // what is left of a chunk after a short read
+    Mux(mux::RecvStream, Bytes),
+}
+
+impl RecvStream {
+    fn mux(recv: mux::RecvStream) -> Self {
+        RecvStream::Mux(recv, Bytes::new())
+    }
+
+    /// Up to `max` bytes, or None once the peer finished the stream.
+    pub async fn read_chunk(&mut self, max: usize) -> Result<Option<Bytes>> {
+        match self {
+            RecvStream::Quic(recv) => Ok(recv.read_chunk(max, true).await?.map(|c| c.bytes)),
+            RecvStream::Mux(recv, pending) => {
+                if pending.is_empty() {
+                    *pending = match recv.read_chunk().await? {
+                        Some(d) => d,
+                        None => return Ok(None),
+                    };
+                }
+                Ok(Some(pending.split_to(max.min(pending.len()))))
+            }
+        }
+    }
+
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        if let RecvStream::Quic(recv) = self {
+            recv.read_exact(buf).await?;
+            return Ok(());
+        }
+        let mut filled = 0;
+        while filled < buf.len() {
+            let d = match self.read_chunk(buf.len() - filled).await? {
+                Some(d) => d,
+                None => return Err(anyhow::anyhow!("eof")),
+            };
+            buf[filled..filled + d.len()].copy_from_slice(&d);
+            filled += d.len();
+        }
+        Ok(())
+    }
+}
+
+impl Info {
+    fn new(
+        kind: &'static str,
+        remote: SocketAddr,
+        tls: &rustls::CommonState,
+        rtt: Duration,
+    ) -> Self {
+        Self {
+            kind,
+            remote,
+            alpn: tls.alpn_protocol().map(|p| p.to_vec()),
+            certs: tls.peer_certificates().map(|c| c.to_vec()),
+            rtt,
+            session: None,
+            dest: None,
+        }
+    }
+}
+
+/// Runs the session protocol over one stream of a QUIC connection carrying many sessions, as a
+/// client daemon's does. `session` and `dest` are what the stream started with.
+pub fn multiplexed(
+    conn: &quinn::Connection,
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    session: Vec<u8>,
+    dest: Option<String>,
+    client: bool,
+) -> Connection {
+    let quic = Connection::Quic(conn.clone());
+    let info = Info {
+        kind: "quic-mux",
+        remote: quic.remote_address(),
+        alpn: quic.alpn(),
+        certs: quic.peer_certificates(),
+        rtt: quic.rtt(),
+        session: Some(session),
+        dest,
+    };
+    Connection::Mux(
+        mux::Connection::new(tokio::io::join(recv, send), client),
+        Arc::new(info),
+    )
+}
+
+async fn connect_tls(
+    config: Arc<rustls::ClientConfig>,
+    tcp: tokio::net::TcpStream,
+    server_name: &str,
+) -> Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+    tcp.set_nodelay(true)?;
+    let server_name = rustls::ServerName::try_from(server_name)?;
+    let tls = tokio_rustls::TlsConnector::from(config)
+        .connect(server_name, tcp)
+        .await?;
+    Ok(tls)
+}
+
+/// Connects to `addr` over TCP and runs the session protocol over TLS on it.
+pub async fn connect_tcp(
+    config: Arc<rustls::ClientConfig>,
+    addr: SocketAddr,
+    server_name: &str,
+) -> Result<Connection> {
+    let start = tokio::time::Instant::now();
+    let tcp = tokio::net::TcpStream::connect(addr).await?;
+    let tls = connect_tls(config, tcp, server_name).await?;
+    let info = Info::new("tcp", addr, tls.get_ref().1, start.elapsed() / 2);
+    Ok(Connection::Mux(
+        mux::Connection::new(tls, true),
+        Arc::new(info),
+    ))
+}
+
+/// Connects to `target` (`host:port`), through `proxy` if given, and runs the session
+/// protocol over a WebSocket inside TLS.
+pub async fn connect_ws(
+    config: Arc<rustls::ClientConfig>,
+    target: &str,
+    server_name: &str,
+    proxy: Option<&http_proxy::Proxy>,
+) -> Result<Connection> {
+    let tcp = match proxy {
+        Some(proxy) => proxy.connect(target).await?,
+        None => tokio::net::TcpStream::connect(target).await?,
+    };
+    let remote = tcp.peer_addr()?;
+    let tls = connect_tls(config, tcp, server_name).await?;
+    let mut info = Info::new("ws", remote, tls.get_ref().1, Duration::ZERO);
+    // the upgrade is exactly one round trip, whatever the proxy added before it
+    let start = tokio::time::Instant::now();
+    let (ws, _) = tokio_tungstenite::client_async(format!("wss://{}/", target), tls).await?;
+    info.rtt = start.elapsed();
+    Ok(Connection::Mux(
+        mux::Connection::new(ws::WsStream::new(ws), true),
+        Arc::new(info),
+    ))
+}
+
+/// Runs the server side of the TLS handshake on an accepted TCP connection.
+pub async fn accept_tcp(
+    acceptor: tokio_rustls::TlsAcceptor,
+    tcp: tokio::net::TcpStream,
+    remote: SocketAddr,
+) -> Result<Connection> {
+    let start = tokio::time::Instant::now();
+    tcp.set_nodelay(true)?;
+    let tls = acceptor.accept(tcp).await?;
+    let info = Info::new("tcp", remote, tls.get_ref().1, start.elapsed());
+    Ok(Connection::Mux(
+        mux::Connection::new(tls, false),
+        Arc::new(info),
+    ))
+}
+
+/// Like `accept_tcp`, then takes the WebSocket upgrade inside TLS.
+pub async fn accept_ws(
+    acceptor: tokio_rustls::TlsAcceptor,
+    tcp: tokio::net::TcpStream,
+    remote: SocketAddr,
+) -> Result<Connection> {
+    let start = tokio::time::Instant::now();
+    tcp.set_nodelay(true)?;
+    let tls = acceptor.accept(tcp).await?;
+    let info = Info::new("ws", remote, tls.get_ref().1, start.elapsed());
+    let ws = tokio_tungstenite::accept_async(tls).await?;
+    Ok(Connection::Mux(
+        mux::Connection::new(ws::WsStream::new(ws), false),
+        Arc::new(info),
+    ))
+}
+
+/// Runs the session protocol over TLS on a stream the relay spliced through to the server.
+pub async fn connect_relayed(
+    config: Arc<rustls::ClientConfig>,
+    stream: relay::Stream,
+    remote: SocketAddr,
+    server_name: &str,
+) -> Result<Connection> {
+    let start = tokio::time::Instant::now();
+    let server_name = rustls::ServerName::try_from(server_name)?;
+    let tls = tokio_rustls::TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await?;
+    let info = Info::new("relay", remote, tls.get_ref().1, start.elapsed() / 2);
+    Ok(Connection::Mux(
+        mux::Connection::new(tls, true),
+        Arc::new(info),
+    ))
+}
+
+/// Runs the server side of the TLS handshake on a client spliced in by the relay.
+pub async fn accept_relayed(
+    acceptor: tokio_rustls::TlsAcceptor,
+    stream: relay::Stream,
+    remote: SocketAddr,
+) -> Result<Connection> {
+    let start = tokio::time::Instant::now();
+    let tls = acceptor.accept(stream).await?;
+    let info = Info::new("relay", remote, tls.get_ref().1, start.elapsed());
+    Ok(Connection::Mux(
+        mux::Connection::new(tls, false),
+        Arc::new(info),
+    ))
+}
@@ -0,0 +1,359 @@
+use crate::{datagram, transport};
+use anyhow::Result;
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, Notify},
+};
+
+// a flow quiet for this long is forgotten, and the next datagram opens it again
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+// sockets a session may hold on the server
+const MAX_FLOWS: usize = 256;
+// datagrams from the peer waiting to be delivered, newer ones are dropped
+pub const QUEUE: usize = 1024;
+
+/// Datagrams between a local UDP port on the client and the `--udp-forward` target of the
+/// server, carried as QUIC datagrams. Every local peer is a flow, with a socket of its own on
+/// the server so replies find their way back. The flows belong to the session and outlive
+/// reconnects, but nothing is replayed: what is sent while the client is away is lost.
+#[derive(Clone)]
+pub enum Tunnel {
+    Client(Arc<Client>),
+    Server(Arc<Server>),
+}
+
+/// The connection the session is on now.
+#[derive(Default)]
+struct Link(Mutex<Option<transport::Connection>>);
+
+impl Link {
+    /// Sends a datagram of a flow to the peer, if we are connected. It is dropped otherwise.
+    fn send(&self, id: u32, payload: &[u8]) {
+        let conn = match self.0.lock().unwrap().clone() {
+            Some(conn) => conn,
+            None => return,
+        };
+        if let Err(e) = conn.send_datagram(datagram::udp(id, payload)) {
+            log::debug!("UDP datagram of {} bytes dropped: {:#}", payload.len(), e);
+        }
+    }
+}
+
+/// The client end, taking datagrams on a local port.
+pub struct Client {
+    link: Link,
+    socket: UdpSocket,
+    flows: Mutex<Flows>,
+}
+
+/// The server end, sending every flow on to the target.
+pub struct Server {
+    link: Link,
+    target: SocketAddr,
+    flows: Mutex<HashMap<u32, Arc<Flow>>>,
+    // beyond this many flows, the one quiet for longest makes room
+    max_flows: usize,
+}
+
+/// The local peers of the client, by the flow ID they go by.
+#[derive(Default)]
+struct Flows {
+    next: u32,
+    by_peer: HashMap<SocketAddr, (u32, Instant)>,
+    by_id: HashMap<u32, SocketAddr>,
+}
+
+impl Flows {
+    fn id(&mut self, peer: SocketAddr, now: Instant) -> u32 {
+        if let Some((id, last)) = self.by_peer.get_mut(&peer) {
+            *last = now;
+            return *id;
+        }
+        // a new peer is the time to drop the old ones
+        self.by_peer
+            .retain(|_, (_, last)| now.duration_since(*last) < FLOW_TIMEOUT);
+        let by_peer = &self.by_peer;
+        self.by_id.retain(|_, peer| by_peer.contains_key(peer));
+
+        let id = self.next;
+        self.next = self.next.wrapping_add(1);
+        self.by_peer.insert(peer, (id, now));
+        self.by_id.insert(id, peer);
+        id
+    }
+
+    fn peer(&mut self, id: u32, now: Instant) -> Option<SocketAddr> {
+        let peer = *self.by_id.get(&id)?;
+        if let Some((_, last)) = self.by_peer.get_mut(&peer) {
+            *last = now;
+        }
+        Some(peer)
+    }
+}
+
+/// The server's socket for one flow, connected to the target.
+struct Flow {
+    socket: UdpSocket,
+    last: Mutex<Instant>,
+    // stops the task reading replies, and with it the socket
+    evicted: Notify,
+}
+
+impl Flow {
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.last.lock().unwrap()
+    }
+
+    fn idle(&self) -> bool {
+        self.last().elapsed() >= FLOW_TIMEOUT
+    }
+}
+
+impl Tunnel {
+    /// The client end, taking datagrams on `addr`.
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        log::info!("Forwarding UDP from {}", socket.local_addr()?);
+        let client = Arc::new(Client {
+            link: Link::default(),
+            socket,
+            flows: Default::default(),
+        });
+        let c = client.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 65536];
+            loop {
+                let (n, peer) = match c.socket.recv_from(&mut buf).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::debug!("UDP recv error: {:?}", e);
+                        continue;
+                    }
+                };
+                let id = c.flows.lock().unwrap().id(peer, Instant::now());
+                c.link.send(id, &buf[..n]);
+            }
+        });
+        Ok(Tunnel::Client(client))
+    }
+
+    /// The server end, sending every flow on to `target`.
+    pub fn forward(target: SocketAddr) -> Self {
+        Tunnel::Server(Arc::new(Server {
+            link: Link::default(),
+            target,
+            flows: Default::default(),
+            max_flows: MAX_FLOWS,
+        }))
+    }
+
+    /// Carries the flows over `conn`, until the next connection of the session takes over.
+    pub async fn run(
+        &self,
+        conn: transport::Connection,
+        mut datagrams: mpsc::Receiver<Bytes>,
+    ) -> Result<()> {
+        let link = match self {
+            Tunnel::Client(client) => &client.link,
+            Tunnel::Server(server) => &server.link,
+        };
+        *link.0.lock().unwrap() = Some(conn);
+        while let Some(d) = datagrams.recv().await {
+            let id = u32::from_be_bytes([d[0], d[1], d[2], d[3]]);
+            let payload = d.slice(4..);
+            match self {
+                Tunnel::Client(client) => client.deliver(id, payload).await,
+                Tunnel::Server(server) => server.deliver(id, payload).await,
+            }
+        }
+        // the stream tasks report why the connection went away
+        std::future::pending().await
+    }
+}
+
+impl Client {
+    /// Passes on a datagram of a flow to its local peer.
+    async fn deliver(&self, id: u32, payload: Bytes) {
+        let peer = match self.flows.lock().unwrap().peer(id, Instant::now()) {
+            Some(peer) => peer,
+            None => return,
+        };
+        if let Err(e) = self.socket.send_to(&payload, peer).await {
+            log::debug!("UDP send error: {:?}", e);
+        }
+    }
+}
+
+impl Server {
+    /// Passes on a datagram of a flow to the target, opening the flow if it is new.
+    async fn deliver(self: &Arc<Self>, id: u32, payload: Bytes) {
+        let flow = self.flows.lock().unwrap().get(&id).cloned();
+        let flow = match flow {
+            Some(flow) => flow,
+            None => match self.open(id).await {
+                Ok(flow) => flow,
+                Err(e) => {
+                    log::debug!("UDP flow {} failed: {:#}", id, e);
+                    return;
+                }
+            },
+        };
+        flow.touch();
+        if let Err(e) = flow.socket.send(&payload).await {
+            log::debug!("UDP send error: {:?}", e);
+        }
+    }
+
+    /// Opens the socket for a flow, and sends what comes back to the client.
+    async fn open(self: &Arc<Self>, id: u32) -> Result<Arc<Flow>> {
+        let bind: SocketAddr = match self.target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(self.target).await?;
+        let flow = Arc::new(Flow {
+            socket,
+            last: Mutex::new(Instant::now()),
+            evicted: Notify::new(),
+        });
+        {
+            let mut flows = self.flows.lock().unwrap();
+            if flows.len() >= self.max_flows {
+                let oldest = flows
+                    .iter()
+                    .min_by_key(|(_, flow)| flow.last())
+                    .map(|(id, _)| *id);
+                if let Some(flow) = oldest.and_then(|id| flows.remove(&id)) {
+                    flow.evicted.notify_one();
+                }
+            }
+            flows.insert(id, flow.clone());
+        }
+
+        let s = self.clone();
+        let f = flow.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 65536];
+            loop {
+                let recv = tokio::time::timeout(FLOW_TIMEOUT, f.socket.recv(&mut buf));
+                let ret = tokio::select! {
+                    ret = recv => ret,
+                    _ = f.evicted.notified() => break,
+                };
+                match ret {
+                    Ok(Ok(n)) => {
+                        f.touch();
+                        s.link.send(id, &buf[..n]);
+                    }
+                    Ok(Err(e)) => {
+                        log::debug!("UDP flow {} closed: {:?}", id, e);
+                        break;
+                    }
+                    Err(_) if f.idle() => break,
+                    Err(_) => {}
+                }
+            }
+            let mut flows = s.flows.lock().unwrap();
+            if flows.get(&id).is_some_and(|flow| Arc::ptr_eq(flow, &f)) {
+                flows.remove(&id);
+            }
+        });
+        Ok(flow)
+    }
+}
+
+/// Carries the session's flows over `conn`, if it has any.
+pub async fn run(
+    tunnel: Option<Tunnel>,
+    conn: transport::Connection,
+    datagrams: mpsc::Receiver<Bytes>,
+) -> Result<()> {
+    match tunnel {
+        Some(tunnel) => tunnel.run(conn, datagrams).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The stream of a session that only carries datagrams: nothing to read, ever.
+pub struct Idle;
+
+impl tokio::io::AsyncRead for Idle {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Flows, Server, Tunnel, FLOW_TIMEOUT};
+    use bytes::Bytes;
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_flows() {
+        let mut flows = Flows::default();
+        let now = Instant::now();
+        let a = "127.0.0.1:5000".parse().unwrap();
+        let b = "127.0.0.1:5001".parse().unwrap();
+        assert_eq!(flows.id(a, now), 0);
+        assert_eq!(flows.id(b, now), 1);
+        assert_eq!(flows.id(a, now), 0);
+        assert_eq!(flows.peer(1, now), Some(b));
+        assert_eq!(flows.peer(2, now), None);
+
+        // a reply keeps a flow open, and a new peer drops the quiet ones
+        let later = now + FLOW_TIMEOUT - Duration::from_secs(1);
+        assert_eq!(flows.peer(1, later), Some(b));
+        let c = "127.0.0.1:5002".parse().unwrap();
+        assert_eq!(flows.id(c, now + FLOW_TIMEOUT), 2);
+        assert_eq!(flows.peer(0, now + FLOW_TIMEOUT), None);
+        assert_eq!(flows.peer(1, now + FLOW_TIMEOUT), Some(b));
+        assert_eq!(flows.id(a, now + FLOW_TIMEOUT), 3);
+    }
+
+    #[tokio::test]
+    async fn test_max_flows() {
+        let target = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = match Tunnel::forward(target.local_addr().unwrap()) {
+            Tunnel::Server(server) => Arc::new(Server {
+                max_flows: 3,
+                ..Arc::into_inner(server).unwrap()
+            }),
+            Tunnel::Client(_) => panic!("not the server end"),
+        };
+        let mut buf = [0; 16];
+        for id in 0..3 {
+            server.deliver(id, Bytes::from_static(b"hi")).await;
+            target.recv_from(&mut buf).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // the flow quiet for longest goes, whichever ID it has
+        server.deliver(0, Bytes::from_static(b"hi")).await;
+        server.deliver(7, Bytes::from_static(b"hi")).await;
+        let flows = server.flows.lock().unwrap();
+        let mut ids: Vec<_> = flows.keys().copied().collect();
+        ids.sort();
+        assert_eq!(ids, [0, 2, 7]);
+    }
+}
@@ -1,32 +1,253 @@
-use crate::{pkt_buf, queue};
+use crate::{
+    ack, compress, datagram, endpoints, heartbeat, pkt_buf, queue, ratelimit, spill, transport, udp,
+};
 use anyhow::Result;
+use bytes::{Bytes, BytesMut};
 use std::{
-    net::{SocketAddr, ToSocketAddrs},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
     sync::Arc,
+    time::Duration,
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::{Mutex, RwLock},
+    sync::{mpsc, watch, Mutex, Notify, RwLock},
 };
 use x509_parser::{der_parser::asn1_rs::FromDer, extensions::GeneralName};
 
 const CHUNK_SIZE: usize = 4096;
+// packet lengths are u16 on the wire
+const MAX_CHUNK_SIZE: usize = 32768;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ConnOpt {
+    #[clap(long = "heartbeat", default_value = "500ms", value_parser = parse_duration)]
+    heartbeat: Duration,
+
+    #[clap(long = "heartbeat-misses", default_value = "3")]
+    heartbeat_misses: u32,
+
+    #[clap(long = "ack-delay", default_value = "20ms", value_parser = parse_duration)]
+    ack_delay: Duration,
+
+    #[clap(long = "ack-bytes", default_value = "256K", value_parser = parse_size)]
+    ack_bytes: u64,
+
+    #[clap(long = "ack-datagram")]
+    ack_datagram: bool,
+
+    #[clap(long = "no-compression")]
+    no_compression: bool,
+
+    #[clap(long = "zstd-level", default_value = "3")]
+    zstd_level: i32,
+}
+
+impl ConnOpt {
+    pub fn config(&self) -> ConnConfig {
+        ConnConfig {
+            heartbeat: heartbeat::Heartbeat::new(self.heartbeat, self.heartbeat_misses),
+            ack: ack::AckPolicy::new(self.ack_delay, self.ack_bytes as usize, self.ack_datagram),
+            zstd_level: self.zstd_level,
+        }
+    }
+
+    pub fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        compress::Codec::alpn_protocols(!self.no_compression)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnConfig {
+    pub heartbeat: heartbeat::Heartbeat,
+    pub ack: ack::AckPolicy,
+    pub zstd_level: i32,
+}
+
+impl ConnConfig {
+    /// For the sessions of a client daemon, whose shared connection QUIC keeps alive.
+    pub fn without_heartbeat(mut self) -> Self {
+        self.heartbeat = heartbeat::Heartbeat::new(Duration::ZERO, 0);
+        self
+    }
+}
+
+/// Replay state of one session, shared by all of its connections.
+#[derive(Clone)]
+pub struct Session {
+    pub q: Arc<Mutex<queue::Queue>>,
+    pub last_ack: Arc<RwLock<u32>>,
+    pub stats: Arc<compress::Stats>,
+    pub shaper: ratelimit::Shaper,
+    // set for a session that carries UDP flows
+    pub udp: Option<udp::Tunnel>,
+    // notified each time the peer takes a connection for the session
+    pub established: Arc<Notify>,
+}
+
+impl Session {
+    pub fn new(bufsize: u8, spill: Option<spill::Spill>, shaper: ratelimit::Shaper) -> Self {
+        Self {
+            q: Arc::new(Mutex::new(queue::Queue::with_spill(bufsize, spill))),
+            last_ack: Arc::new(RwLock::new(0_u32)),
+            stats: Arc::new(compress::Stats::new()),
+            shaper,
+            udp: None,
+            established: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn with_udp(mut self, tunnel: udp::Tunnel) -> Self {
+        self.udp = Some(tunnel);
+        self
+    }
+}
+
+// a destination requested by the client is carried in its certificate as a URI like this
+const DEST_SCHEME: &str = "ssh://";
+// and a session that carries UDP to the server's --udp-forward target by this one
+const UDP_URI: &str = "udp:";
+// a client daemon, whose connection carries many sessions, has this one
+const MULTIPLEXED_URI: &str = "mux:";
 
 pub fn gen_cert() -> Result<(Vec<u8>, Vec<u8>)> {
+    gen_cert_with_dest(None)
+}
+
+/// A client certificate that also asks the server for `dest` instead of its `--forward` target.
+/// The certificate names the session, so the destination can't change while it lasts.
+pub fn gen_cert_with_dest(dest: Option<&str>) -> Result<(Vec<u8>, Vec<u8>)> {
+    gen_cert_with_uri(dest.map(|dest| format!("{}{}", DEST_SCHEME, dest)))
+}
+
+/// A client certificate for a session that carries UDP flows instead of a stream.
+pub fn gen_cert_udp() -> Result<(Vec<u8>, Vec<u8>)> {
+    gen_cert_with_uri(Some(UDP_URI.to_string()))
+}
+
+/// A client certificate for a client daemon, which runs sessions over streams of one connection.
+pub fn gen_cert_multiplexed() -> Result<(Vec<u8>, Vec<u8>)> {
+    gen_cert_with_uri(Some(MULTIPLEXED_URI.to_string()))
+}
+
+fn gen_cert_with_uri(uri: Option<String>) -> Result<(Vec<u8>, Vec<u8>)> {
     let host: String = match hostname::get()?.into_string() {
         Ok(h) => h,
         Err(_) => "localhost".to_string(),
     };
-    let cert = rcgen::generate_simple_self_signed(vec![host])?;
+    let mut params = rcgen::CertificateParams::new(vec![host]);
+    if let Some(uri) = uri {
+        params.subject_alt_names.push(rcgen::SanType::URI(uri));
+    }
+    let cert = rcgen::Certificate::from_params(params)?;
     let cert_der = cert.serialize_der()?;
     let priv_key = cert.serialize_private_key_der();
     Ok((cert_der, priv_key))
 }
 
+/// Parses durations such as `250ms`, `2s`, `5m`, `1h` or `7d`. A bare number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(pos);
+    let num: u64 = num
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid duration: {}", s))?;
+    let secs = match unit {
+        "ms" => return Ok(Duration::from_millis(num)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(anyhow::anyhow!("invalid duration unit: {}", s)),
+    };
+    num.checked_mul(secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow::anyhow!("duration too large: {}", s))
+}
+
+/// Parses byte sizes such as `512K`, `16MiB` or `1GB` (binary units either way). A bare number is bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(pos);
+    let num: u64 = num
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid size: {}", s))?;
+    let shift = match unit {
+        "" | "B" => 0,
+        "K" | "k" | "KB" | "kB" | "KiB" => 10,
+        "M" | "m" | "MB" | "MiB" => 20,
+        "G" | "g" | "GB" | "GiB" => 30,
+        _ => return Err(anyhow::anyhow!("invalid size unit: {}", s)),
+    };
+    num.checked_mul(1 << shift)
+        .ok_or_else(|| anyhow::anyhow!("size too large: {}", s))
+}
+
+/// Formats a byte size with the largest binary unit that divides it, the reverse of `parse_size`.
+pub fn format_size(n: u64) -> String {
+    for (shift, unit) in [(30, "G"), (20, "M"), (10, "K")] {
+        if n != 0 && n.trailing_zeros() >= shift {
+            return format!("{}{}", n >> shift, unit);
+        }
+    }
+    n.to_string()
+}
+
+/// Splits `host:ports`, where ports is a list such as `2222,2230-2239`.
+pub fn split_ports(target: &str) -> Result<(&str, Vec<u16>)> {
+    let (host, spec) = target
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("expected host:port: {}", target))?;
+    let invalid = || anyhow::anyhow!("invalid ports: {}", spec);
+    let mut ports = Vec::new();
+    for part in spec.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let first: u16 = first.parse().map_err(|_| invalid())?;
+                let last: u16 = last.parse().map_err(|_| invalid())?;
+                if first > last {
+                    return Err(invalid());
+                }
+                ports.extend(first..=last);
+            }
+            None => ports.push(part.parse().map_err(|_| invalid())?),
+        }
+    }
+    Ok((host, ports))
+}
+
+/// The host part of `target`, used as the TLS server name so session tickets are cached per server.
+pub fn server_name(target: &str) -> &str {
+    let host = match split_ports(target) {
+        Ok((host, _)) => host,
+        Err(_) => target,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Turns an IPv4-mapped IPv6 address, as dual-stack sockets report IPv4 peers, back into IPv4.
+pub fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.into(),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+/// Resolves `host:ports` to each of the host's addresses on each of the ports, port by port.
 pub fn resolve(target: &str, only4: bool, only6: bool) -> Result<Vec<SocketAddr>> {
-    let targets = target.to_socket_addrs()?;
+    let (host, ports) = split_ports(target)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = (host, 0).to_socket_addrs()?.collect::<Vec<_>>();
+    let targets = ports
+        .iter()
+        .flat_map(|port| addrs.iter().map(|addr| SocketAddr::new(addr.ip(), *port)))
+        .collect::<Vec<_>>();
     log::debug!("Resolved targets: {:?}", targets);
-    let targets = targets.filter(|addr| {
+    let targets = targets.into_iter().filter(|addr| {
         if !only4 && !only6 {
             return true;
         }
@@ -41,11 +262,26 @@ pub fn resolve(target: &str, only4: bool, only6: bool) -> Result<Vec<SocketAddr>
     let targets = targets.collect::<Vec<SocketAddr>>();
     Ok(targets)
 }
-pub struct SkipClientVerification;
+
+/// The addresses of `host:ports`, for options that may listen on several.
+#[derive(Debug, Clone)]
+pub struct Addrs(pub Vec<SocketAddr>);
+
+pub fn parse_addrs(s: &str) -> Result<Addrs> {
+    Ok(Addrs(resolve(s, false, false)?))
+}
+pub struct SkipClientVerification {
+    optional: bool,
+}
 
 impl SkipClientVerification {
     pub fn new() -> Arc<Self> {
-        Arc::new(Self)
+        Arc::new(Self { optional: false })
+    }
+
+    /// Lets clients without a certificate finish the handshake too, quicssh-rs sends none.
+    pub fn optional() -> Arc<Self> {
+        Arc::new(Self { optional: true })
     }
 }
 
@@ -53,6 +289,9 @@ impl rustls::server::ClientCertVerifier for SkipClientVerification {
     fn client_auth_root_subjects(&self) -> &[rustls::DistinguishedName] {
         &[]
     }
+    fn client_auth_mandatory(&self) -> bool {
+        !self.optional
+    }
     fn verify_client_cert(
         &self,
         _end_entity: &rustls::Certificate,
@@ -99,6 +338,38 @@ pub fn x509(cert: &rustls::Certificate) -> Result<(Vec<u8>, Option<String>)> {
     Ok((peer.public_key().subject_public_key.data.to_vec(), name))
 }
 
+/// The destination the client asked for, if any.
+pub fn x509_dest(cert: &rustls::Certificate) -> Result<Option<String>> {
+    Ok(x509_uris(cert)?
+        .iter()
+        .find_map(|uri| uri.strip_prefix(DEST_SCHEME).map(str::to_string)))
+}
+
+pub fn x509_udp(cert: &rustls::Certificate) -> Result<bool> {
+    Ok(x509_uris(cert)?.iter().any(|uri| uri == UDP_URI))
+}
+
+pub fn x509_multiplexed(cert: &rustls::Certificate) -> Result<bool> {
+    Ok(x509_uris(cert)?.iter().any(|uri| uri == MULTIPLEXED_URI))
+}
+
+fn x509_uris(cert: &rustls::Certificate) -> Result<Vec<String>> {
+    let (_, peer) = x509_parser::prelude::X509Certificate::from_der(&cert.0)?;
+    let uris = match peer.subject_alternative_name()? {
+        Some(san) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::URI(uri) => Some(uri.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Ok(uris)
+}
+
 pub fn pubkey_to_id(pubkey: &[u8]) -> String {
     let mut sum = sha256::digest(pubkey.to_vec());
     sum.truncate(8);
@@ -128,32 +399,57 @@ pub async fn handle_connection<
     Reader: tokio::io::AsyncRead + Send + Sync + Unpin,
     Writer: tokio::io::AsyncWrite + Send + Sync + Unpin,
 >(
-    conn: quinn::Connection,
-    q: Arc<Mutex<queue::Queue>>,
-    last_ack: Arc<RwLock<u32>>,
+    conn: transport::Connection,
+    rx_stream: Option<(transport::SendStream, transport::RecvStream)>,
+    config: ConnConfig,
+    session: Session,
+    endpoints: endpoints::Endpoints,
     recv: Reader,
     send: Writer,
 ) -> Result<()> {
-    let tx = handle_connection_tx(conn.clone(), recv, q);
-    let rx = handle_connection_rx(conn.clone(), last_ack, send);
+    let codec = compress::Codec::negotiated(&conn);
+    log::debug!("transport: {}, codec: {:?}", conn.kind(), codec);
+    session.stats.bind(codec)?;
+    let encoder = compress::Encoder::new(codec, config.zstd_level)?;
+    let decoder = compress::Decoder::new(codec, MAX_CHUNK_SIZE)?;
+
+    let (hb_tx, hb_rx) = mpsc::channel(1);
+    // the initial value is never read, only the ones the peer sends
+    let (ack_tx, ack_rx) = watch::channel(0);
+    let (ep_tx, ep_rx) = watch::channel(Bytes::new());
+    let (udp_tx, udp_rx) = mpsc::channel(udp::QUEUE);
+    let udp = udp::run(session.udp.clone(), conn.clone(), udp_rx);
+    let tx = handle_connection_tx(conn.clone(), recv, session.clone(), encoder, ack_rx);
+    let rx = handle_connection_rx(conn.clone(), rx_stream, config.ack, session, decoder, send);
+    let hb = config.heartbeat.run(conn.clone(), hb_rx);
+    let ep = endpoints.run(conn.clone(), ep_rx);
+    let dg = datagram::dispatch(conn.clone(), hb_tx, ack_tx, ep_tx, udp_tx);
 
     tokio::select! {
         val = tx => {val?;},
         val = rx => {val?;},
+        val = hb => {val?;},
+        val = ep => {val?;},
+        val = dg => {val?;},
+        val = udp => {val?;},
     }
     Ok(())
 }
 
 pub async fn handle_connection_tx<Reader: tokio::io::AsyncRead + Send + Sync + Unpin>(
-    conn: quinn::Connection,
+    conn: transport::Connection,
     recv: Reader,
-    q: Arc<Mutex<queue::Queue>>,
+    session: Session,
+    encoder: compress::Encoder,
+    ack_datagrams: watch::Receiver<u32>,
 ) -> Result<()> {
     let (mut quic_send, mut quic_recv) = conn.accept_bi().await?;
-    send_buf(q.clone(), &mut quic_recv, &mut quic_send).await?;
+    // the server opens this stream only once the session is set up on its side
+    session.established.notify_one();
+    send_buf(session.q.clone(), &mut quic_recv, &mut quic_send).await?;
 
-    let reader2quic = pipe_reader_to_quic(recv, quic_send, q.clone());
-    let ack = consume_ack(q, quic_recv);
+    let ack = consume_ack(session.q.clone(), quic_recv, ack_datagrams);
+    let reader2quic = pipe_reader_to_quic(recv, quic_send, session, encoder);
 
     tokio::select! {
         val = reader2quic => val?,
@@ -163,14 +459,20 @@ pub async fn handle_connection_tx<Reader: tokio::io::AsyncRead + Send + Sync + U
 }
 
 pub async fn handle_connection_rx<Writer: tokio::io::AsyncWrite + Send + Sync + Unpin>(
-    conn: quinn::Connection,
-    last_ack: Arc<RwLock<u32>>,
+    conn: transport::Connection,
+    rx_stream: Option<(transport::SendStream, transport::RecvStream)>,
+    ack: ack::AckPolicy,
+    session: Session,
+    decoder: compress::Decoder,
     send: Writer,
 ) -> Result<()> {
-    let (mut quic_send, quic_recv) = conn.open_bi().await?;
-    request_buf(last_ack.clone(), &mut quic_send).await?;
+    let (quic_send, quic_recv) = match rx_stream {
+        Some(v) => v,
+        None => open_rx(&conn, session.last_ack.clone()).await?,
+    };
 
-    let quic2writer = pipe_quic_to_writer(quic_recv, quic_send, last_ack, send);
+    let acker = ack::Acker::new(ack, conn.clone(), quic_send);
+    let quic2writer = pipe_quic_to_writer(quic_recv, acker, session, decoder, send);
 
     tokio::select! {
         val = quic2writer => val?,
@@ -178,7 +480,21 @@ pub async fn handle_connection_rx<Writer: tokio::io::AsyncWrite + Send + Sync +
     Ok(())
 }
 
-pub async fn request_buf(last_ack: Arc<RwLock<u32>>, send: &mut quinn::SendStream) -> Result<()> {
+/// Opens the receiving stream and asks the peer to resend everything after our last ack.
+/// This is the only request the client sends as 0-RTT data, since replaying it is harmless.
+pub async fn open_rx(
+    conn: &transport::Connection,
+    last_ack: Arc<RwLock<u32>>,
+) -> Result<(transport::SendStream, transport::RecvStream)> {
+    let (mut quic_send, quic_recv) = conn.open_bi().await?;
+    request_buf(last_ack, &mut quic_send).await?;
+    Ok((quic_send, quic_recv))
+}
+
+pub async fn request_buf(
+    last_ack: Arc<RwLock<u32>>,
+    send: &mut transport::SendStream,
+) -> Result<()> {
     let last_ack = last_ack.read().await;
     send.write_all(&last_ack.to_be_bytes()).await?;
     Ok(())
@@ -186,56 +502,62 @@ pub async fn request_buf(last_ack: Arc<RwLock<u32>>, send: &mut quinn::SendStrea
 
 pub async fn send_buf(
     q: Arc<Mutex<queue::Queue>>,
-    recv: &mut quinn::RecvStream,
-    send: &mut quinn::SendStream,
+    recv: &mut transport::RecvStream,
+    send: &mut transport::SendStream,
 ) -> Result<()> {
     let mut buf = [0; 4];
-    match recv.read(&mut buf).await? {
-        None => return Err(anyhow::anyhow!("eof")),
-        Some(0) => return Err(anyhow::anyhow!("eof")),
-        Some(4) => {
-            let last_ack = u32::from_be_bytes(buf);
-            log::debug!("last_ack: {}", last_ack);
-            let list = q.lock().await.list(last_ack)?;
-            for (id, d) in list {
-                let pkt = pkt_buf::to_pkt(id, d);
-                send.write_all(&pkt).await?;
-            }
-        }
-        _ => return Err(anyhow::anyhow!("invalid buf")),
+    recv.read_exact(&mut buf).await?;
+    let last_ack = u32::from_be_bytes(buf);
+    log::debug!("last_ack: {}", last_ack);
+    let list = q.lock().await.list(last_ack)?;
+    for (id, frame) in list {
+        let d = frame.load().await?;
+        send.write_all_chunks(&mut pkt_buf::to_pkt(id, d)).await?;
     }
     Ok(())
 }
 
 pub async fn pipe_quic_to_writer<Writer: tokio::io::AsyncWrite + Send + Sync + Unpin>(
-    mut recv: quinn::RecvStream,
-    mut ack: quinn::SendStream,
-    last_ack: Arc<RwLock<u32>>,
-    mut send: Writer,
+    mut recv: transport::RecvStream,
+    mut acker: ack::Acker,
+    session: Session,
+    mut decoder: compress::Decoder,
+    send: Writer,
 ) -> Result<()> {
-    let mut buf = [0; CHUNK_SIZE];
+    let mut send = tokio::io::BufWriter::with_capacity(MAX_CHUNK_SIZE, send);
     let mut databuf = pkt_buf::DataBuf::new();
     loop {
-        match recv.read(&mut buf).await? {
-            None => break,
-            Some(0) => break,
-            Some(n) => {
-                log::debug!("quic recv {} bytes", n);
-
-                databuf.push(buf[..n].to_vec());
-                loop {
-                    match databuf.next() {
-                        Some((id, d)) => {
-                            send.write_all(&d).await?;
-                            send.flush().await?;
-                            ack.write_all(&pkt_buf::to_ack_pkt(id)).await?;
-                            let mut last_ack = last_ack.write().await;
-                            *last_ack = id;
-                        }
-                        None => break,
-                    }
-                }
+        let chunk = tokio::select! {
+            chunk = recv.read_chunk(usize::MAX) => chunk?,
+            _ = acker.expired() => {
+                acker.on_timer().await?;
+                continue;
             }
+        };
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => {
+                acker.finish().await;
+                break;
+            }
+        };
+        log::debug!("quic recv {} bytes", chunk.len());
+        session.shaper.rx(chunk.len()).await;
+        databuf.push(chunk);
+
+        // everything that arrived together is written and flushed together
+        let mut delivered = None;
+        let mut bytes = 0;
+        for (id, d) in databuf.by_ref() {
+            let d = decoder.decode(d, &session.stats)?;
+            send.write_all(&d).await?;
+            delivered = Some(id);
+            bytes += d.len();
+        }
+        if let Some(id) = delivered {
+            send.flush().await?;
+            *session.last_ack.write().await = id;
+            acker.delivered(id, bytes).await?;
         }
     }
     Ok(())
@@ -243,45 +565,391 @@ pub async fn pipe_quic_to_writer<Writer: tokio::io::AsyncWrite + Send + Sync + U
 
 pub async fn pipe_reader_to_quic<Reader: tokio::io::AsyncRead + Send + Sync + Unpin>(
     mut recv: Reader,
-    mut send: quinn::SendStream,
-    q: Arc<Mutex<queue::Queue>>,
+    mut send: transport::SendStream,
+    session: Session,
+    mut encoder: compress::Encoder,
 ) -> Result<()> {
-    let mut buf = [0; CHUNK_SIZE];
+    let mut buf = BytesMut::new();
+    let mut chunk_size = CHUNK_SIZE;
     loop {
-        match recv.read(&mut buf).await {
-            Ok(0) => break,
-            Ok(n) => {
-                log::debug!("reader recv {} bytes", n);
-                let id = q.lock().await.push(buf[..n].to_vec())?;
-                let pkt = pkt_buf::to_pkt(id, buf[..n].to_vec());
-                send.write_all(&pkt).await?;
-            }
-            Err(e) => return Err(e.into()),
+        // read into spare capacity, so the buffer is not zeroed on every read
+        buf.reserve(chunk_size);
+        let n = (&mut recv)
+            .take(chunk_size as u64)
+            .read_buf(&mut buf)
+            .await?;
+        if n == 0 {
+            break;
+        }
+        log::debug!("reader recv {} bytes", n);
+        let d = buf.split().freeze();
+
+        // grow the read size while the reader keeps filling it, shrink it back when traffic is interactive
+        if n == chunk_size {
+            chunk_size = (chunk_size * 2).min(MAX_CHUNK_SIZE);
+        } else if n < chunk_size / 4 {
+            chunk_size = (chunk_size / 2).max(CHUNK_SIZE);
+        }
+
+        // frames are queued encoded, so a replay does not compress them again
+        let d = encoder.encode(d, &session.stats)?;
+        session.shaper.tx(d.len()).await;
+        let (id, spill) = {
+            let mut q = session.q.lock().await;
+            (q.push(d.clone())?, q.over_threshold())
+        };
+        send.write_all_chunks(&mut pkt_buf::to_pkt(id, d)).await?;
+        if spill {
+            queue::spill(&session.q).await;
         }
     }
+    // the peer ends the session when the stream ends, so it has to get there before we go
+    send.finish().await?;
     Ok(())
 }
 
-pub async fn consume_ack(q: Arc<Mutex<queue::Queue>>, mut recv: quinn::RecvStream) -> Result<()> {
-    let mut buf = [0; CHUNK_SIZE];
+pub async fn consume_ack(
+    q: Arc<Mutex<queue::Queue>>,
+    mut recv: transport::RecvStream,
+    mut datagrams: watch::Receiver<u32>,
+) -> Result<()> {
     let mut ackbuf = pkt_buf::AckBuf::new();
     loop {
-        match recv.read(&mut buf).await? {
-            None => break,
-            Some(0) => break,
-            Some(n) => {
-                log::debug!("quic ack recv {} bytes", n);
-                ackbuf.push(buf[..n].to_vec());
-                loop {
-                    match ackbuf.next() {
-                        Some(id) => {
-                            q.lock().await.check(id)?;
-                        }
-                        None => break,
-                    }
+        tokio::select! {
+            chunk = recv.read_chunk(CHUNK_SIZE) => {
+                let chunk = match chunk? {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
+                log::debug!("quic ack recv {} bytes", chunk.len());
+                ackbuf.push(chunk);
+                for id in ackbuf.by_ref() {
+                    q.lock().await.check(id)?;
+                }
+            }
+            Ok(()) = datagrams.changed() => {
+                let id = *datagrams.borrow_and_update();
+                // datagrams may be reordered or repeated, so older acks are expected
+                if let Err(e) = q.lock().await.check(id) {
+                    log::debug!("ignoring ack datagram: {:?}", e);
                 }
             }
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{ack, compress, endpoints, heartbeat, pkt_buf, ratelimit, transport};
+    use bytes::Bytes;
+    use std::{sync::Arc, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// Both ends of a QUIC connection over loopback, the client presenting `cert`.
+    async fn quic_pair(cert: (Vec<u8>, Vec<u8>)) -> (quinn::Connection, quinn::Connection) {
+        let (cert, key) = cert;
+        let crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(super::SkipClientVerification::new())
+            .with_single_cert(
+                vec![rustls::Certificate(cert.clone())],
+                rustls::PrivateKey(key.clone()),
+            )
+            .unwrap();
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let server = quinn::Endpoint::server(config, "[::1]:0".parse().unwrap()).unwrap();
+        let crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(super::SkipServerVerification::new())
+            .with_client_auth_cert(vec![rustls::Certificate(cert)], rustls::PrivateKey(key))
+            .unwrap();
+        let config = quinn::ClientConfig::new(Arc::new(crypto));
+        let client = quinn::Endpoint::client("[::1]:0".parse().unwrap()).unwrap();
+        let connecting = client
+            .connect_with(config, server.local_addr().unwrap(), "localhost")
+            .unwrap();
+        let (client, server) =
+            tokio::join!(connecting, async { server.accept().await.unwrap().await });
+        (client.unwrap(), server.unwrap())
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(
+            super::parse_duration("250ms").unwrap(),
+            Duration::from_millis(250)
+        );
+        assert_eq!(super::parse_duration("2s").unwrap(), Duration::from_secs(2));
+        assert_eq!(super::parse_duration("3").unwrap(), Duration::from_secs(3));
+        assert_eq!(
+            super::parse_duration("5m").unwrap(),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            super::parse_duration("1h").unwrap(),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            super::parse_duration("7d").unwrap(),
+            Duration::from_secs(604800)
+        );
+        assert_eq!(super::parse_duration("0").unwrap(), Duration::ZERO);
+        assert!(super::parse_duration("").is_err());
+        assert!(super::parse_duration("ms").is_err());
+        assert!(super::parse_duration("1.5s").is_err());
+        assert!(super::parse_duration("2w").is_err());
+        assert!(super::parse_duration("18446744073709551615d").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(super::parse_size("4096").unwrap(), 4096);
+        assert_eq!(super::parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(super::parse_size("512KB").unwrap(), 512 << 10);
+        assert_eq!(super::parse_size("512KiB").unwrap(), 512 << 10);
+        assert_eq!(super::parse_size("64B").unwrap(), 64);
+        assert_eq!(super::parse_size("16M").unwrap(), 16 << 20);
+        assert_eq!(super::parse_size("16MiB").unwrap(), 16 << 20);
+        assert_eq!(super::parse_size("1G").unwrap(), 1 << 30);
+        assert!(super::parse_size("").is_err());
+        assert!(super::parse_size("1T").is_err());
+        assert!(super::parse_size("16iB").is_err());
+        assert!(super::parse_size("16Bi").is_err());
+        assert!(super::parse_size("16iiB").is_err());
+        assert!(super::parse_size("16KiiB").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(super::format_size(0), "0");
+        assert_eq!(super::format_size(1000), "1000");
+        assert_eq!(super::format_size(512 << 10), "512K");
+        assert_eq!(super::format_size(3 << 20), "3M");
+        assert_eq!(super::format_size((1 << 30) + (1 << 20)), "1025M");
+    }
+
+    #[test]
+    fn test_server_name() {
+        assert_eq!(super::server_name("example.com:2222"), "example.com");
+        assert_eq!(super::server_name("example.com"), "example.com");
+        assert_eq!(super::server_name("192.0.2.1:2222"), "192.0.2.1");
+        assert_eq!(super::server_name("[::1]:2222"), "::1");
+        assert_eq!(
+            super::server_name("example.com:2222,2230-2239"),
+            "example.com"
+        );
+    }
+
+    #[test]
+    fn test_split_ports() {
+        let (host, ports) = super::split_ports("[::1]:2222,2230-2232").unwrap();
+        assert_eq!(host, "[::1]");
+        assert_eq!(ports, [2222, 2230, 2231, 2232]);
+        assert!(super::split_ports("example.com").is_err());
+        assert!(super::split_ports("example.com:2230-2222").is_err());
+        assert!(super::split_ports("example.com:2222,").is_err());
+        let addrs = super::resolve("127.0.0.1:2222-2223", false, false).unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[1], "127.0.0.1:2223".parse().unwrap());
+    }
+
+    #[test]
+    fn test_x509_dest() {
+        let (cert, _) = super::gen_cert_with_dest(Some("db.internal:5432")).unwrap();
+        let cert = rustls::Certificate(cert);
+        assert_eq!(
+            super::x509_dest(&cert).unwrap().as_deref(),
+            Some("db.internal:5432")
+        );
+        // the hostname is still the name
+        assert!(super::x509(&cert).unwrap().1.is_some());
+        assert!(!super::x509_udp(&cert).unwrap());
+        let (cert, _) = super::gen_cert().unwrap();
+        assert_eq!(super::x509_dest(&rustls::Certificate(cert)).unwrap(), None);
+        let (cert, _) = super::gen_cert_udp().unwrap();
+        let cert = rustls::Certificate(cert);
+        assert!(super::x509_udp(&cert).unwrap());
+        assert_eq!(super::x509_dest(&cert).unwrap(), None);
+        let (cert, _) = super::gen_cert_multiplexed().unwrap();
+        let cert = rustls::Certificate(cert);
+        assert!(super::x509_multiplexed(&cert).unwrap());
+        assert!(!super::x509_udp(&cert).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_final_ack() {
+        let (client, server) = quic_pair(super::gen_cert().unwrap()).await;
+        let (client, server) = (
+            transport::Connection::Quic(client),
+            transport::Connection::Quic(server),
+        );
+        let (mut send, mut acks) = client.open_bi().await.unwrap();
+        send.write_all_chunks(&mut pkt_buf::to_pkt(1, Bytes::from_static(b"bye")))
+            .await
+            .unwrap();
+        send.finish().await.unwrap();
+
+        let (ack_send, recv) = server.accept_bi().await.unwrap();
+        // longer than the test runs, the end of the stream is what sends the ack
+        let policy = ack::AckPolicy::new(Duration::from_secs(60), usize::MAX, true);
+        let acker = ack::Acker::new(policy, server.clone(), ack_send);
+        let session = super::Session::new(18, None, ratelimit::Shaper::unlimited());
+        let decoder = compress::Decoder::new(compress::Codec::Raw, super::MAX_CHUNK_SIZE).unwrap();
+        super::pipe_quic_to_writer(recv, acker, session, decoder, tokio::io::sink())
+            .await
+            .unwrap();
+        let mut buf = vec![0; pkt_buf::to_ack_pkt(1).len()];
+        acks.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, pkt_buf::to_ack_pkt(1));
+    }
+
+    #[tokio::test]
+    async fn test_lost_ack_datagrams() {
+        let (client, server) = quic_pair(super::gen_cert().unwrap()).await;
+        let (client, server) = (
+            transport::Connection::Quic(client),
+            transport::Connection::Quic(server),
+        );
+        let session = super::Session::new(18, None, ratelimit::Shaper::unlimited());
+        let id = session
+            .q
+            .lock()
+            .await
+            .push(Bytes::from_static(b"hi"))
+            .unwrap();
+        let (mut send, acks) = client.open_bi().await.unwrap();
+        send.write_all_chunks(&mut pkt_buf::to_pkt(id, Bytes::from_static(b"hi")))
+            .await
+            .unwrap();
+
+        let (ack_send, recv) = server.accept_bi().await.unwrap();
+        let policy = ack::AckPolicy::new(Duration::from_millis(10), usize::MAX, true);
+        let acker = ack::Acker::new(policy, server.clone(), ack_send);
+        let peer = super::Session::new(18, None, ratelimit::Shaper::unlimited());
+        let decoder = compress::Decoder::new(compress::Codec::Raw, super::MAX_CHUNK_SIZE).unwrap();
+        tokio::spawn(super::pipe_quic_to_writer(
+            recv,
+            acker,
+            peer,
+            decoder,
+            tokio::io::sink(),
+        ));
+        // the ack datagram and its repeat are never read, as if both were lost
+        let (_datagrams, datagrams_rx) = tokio::sync::watch::channel(0);
+        tokio::spawn(super::consume_ack(session.q.clone(), acks, datagrams_rx));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while session.q.lock().await.len() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    /// Opens the stream of session `id` on a client daemon's connection, as both ends do.
+    async fn mux_session(
+        client: &quinn::Connection,
+        server: &quinn::Connection,
+        id: &[u8],
+    ) -> (transport::Connection, transport::Connection) {
+        let (mut send, recv) = client.open_bi().await.unwrap();
+        transport::write_session_header(&mut send, id, None)
+            .await
+            .unwrap();
+        let (server_send, mut server_recv) = server.accept_bi().await.unwrap();
+        let (id, dest) = transport::read_session_header(&mut server_recv)
+            .await
+            .unwrap();
+        (
+            transport::multiplexed(client, send, recv, id.clone(), None, true),
+            transport::multiplexed(server, server_send, server_recv, id, dest, false),
+        )
+    }
+
+    /// Runs one end of a session over `conn`, with `io` as its local side.
+    async fn run(
+        conn: transport::Connection,
+        session: &super::Session,
+        io: &mut DuplexStream,
+    ) -> anyhow::Result<()> {
+        let config = super::ConnConfig {
+            heartbeat: heartbeat::Heartbeat::new(Duration::ZERO, 0),
+            ack: ack::AckPolicy::new(Duration::ZERO, 0, false),
+            zstd_level: 0,
+        };
+        let (recv, send) = tokio::io::split(io);
+        let endpoints = endpoints::Endpoints::Learned(Default::default());
+        super::handle_connection(conn, None, config, session.clone(), endpoints, recv, send).await
+    }
+
+    async fn expect(io: &mut DuplexStream, data: &[u8]) {
+        let mut buf = vec![0; data.len()];
+        io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed() {
+        let cert = super::gen_cert_multiplexed().unwrap();
+        let (client, server) = quic_pair(cert.clone()).await;
+        let session = || super::Session::new(18, None, ratelimit::Shaper::unlimited());
+        let (client_a, server_a, client_b, server_b) = (session(), session(), session(), session());
+        // the ends ssh and sshd see, and the sessions' ends of them
+        let (mut ssh_a, mut io_a) = tokio::io::duplex(1024);
+        let (mut sshd_a, mut backend_a) = tokio::io::duplex(1024);
+        let (mut ssh_b, mut io_b) = tokio::io::duplex(1024);
+        let (mut sshd_b, mut backend_b) = tokio::io::duplex(1024);
+
+        let (a, server_conn_a) = mux_session(&client, &server, &[1; 16]).await;
+        let (b, server_conn_b) = mux_session(&client, &server, &[2; 16]).await;
+        let talk = async {
+            for data in [b"a0", b"a1", b"a2"] {
+                ssh_a.write_all(data).await.unwrap();
+                expect(&mut sshd_a, data).await;
+            }
+            ssh_b.write_all(b"b0").await.unwrap();
+            expect(&mut sshd_b, b"b0").await;
+            sshd_a.write_all(b"A0").await.unwrap();
+            expect(&mut ssh_a, b"A0").await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        tokio::select! {
+            _ = talk => {}
+            ret = run(a, &client_a, &mut io_a) => panic!("{:?}", ret),
+            ret = run(server_conn_a, &server_a, &mut backend_a) => panic!("{:?}", ret),
+            ret = run(b, &client_b, &mut io_b) => panic!("{:?}", ret),
+            ret = run(server_conn_b, &server_b, &mut backend_b) => panic!("{:?}", ret),
+        }
+        // each session counts its own frames
+        let acked_a = *server_a.last_ack.read().await;
+        let acked_b = *server_b.last_ack.read().await;
+        assert_eq!(acked_a, acked_b + 2);
+        assert_eq!(server_b.q.lock().await.len(), 0);
+        client.close(0_u8.into(), b"");
+
+        // what went out with the connection is replayed, what was written meanwhile follows
+        server_a
+            .q
+            .lock()
+            .await
+            .push(Bytes::from_static(b"A1"))
+            .unwrap();
+        ssh_a.write_all(b"a3").await.unwrap();
+        let (client, server) = quic_pair(cert).await;
+        let (a, server_conn_a) = mux_session(&client, &server, &[1; 16]).await;
+        let talk = async {
+            expect(&mut ssh_a, b"A1").await;
+            expect(&mut sshd_a, b"a3").await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        tokio::select! {
+            _ = talk => {}
+            ret = run(a, &client_a, &mut io_a) => panic!("{:?}", ret),
+            ret = run(server_conn_a, &server_a, &mut backend_a) => panic!("{:?}", ret),
+        }
+        assert_eq!(*server_a.last_ack.read().await, acked_a + 1);
+        assert_eq!(server_a.q.lock().await.len(), 0);
+        assert_eq!(*server_b.last_ack.read().await, acked_b);
+    }
+}